@@ -0,0 +1,57 @@
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use simu::{Attributes, Credentials, ReturnCode};
+
+use crate::error::SimuError;
+use crate::helper;
+
+/**
+ * Attributes to change, as sent by the client.
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AttributesRequest {
+    /// In octal, such as `"640"`
+    mode: Option<String>,
+    group: Option<String>,
+    /// Unix time
+    modified: Option<i64>,
+    /// Added to `modified`, for clients keeping times more precisely than seconds
+    #[serde(default)]
+    modified_nanos: u32,
+}
+
+impl AttributesRequest {
+    fn parse(self) -> Option<Attributes> {
+        let mode = match self.mode {
+            Some(mode) => Some(u32::from_str_radix(&mode, 8).ok()?),
+            None => None,
+        };
+        Some(Attributes {
+            mode,
+            group: self.group,
+            modified: self.modified.map(|secs| (secs, self.modified_nanos)),
+        })
+    }
+}
+
+/**
+ * Changes the mode, group or modification time of the file or directory at the path,
+ * as a PATCH with a JSON body. The helper does so as the user, so the kernel decides what they may change.
+ */
+pub async fn patch(
+    credentials: Credentials,
+    req: &HttpRequest,
+    share: &str,
+    path: &[u8],
+    mut payload: Payload,
+) -> Result<HttpResponse, SimuError> {
+    let attributes = web::Json::<AttributesRequest>::from_request(req, &mut payload)
+        .await
+        .ok()
+        .and_then(|body| body.into_inner().parse())
+        .ok_or_else(|| SimuError::new(ReturnCode::InvalidInput))?;
+    helper::run_attributes(credentials, share, path, attributes).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,24 @@
+use std::ffi::CString;
+
+use libc::{c_char, openlog, syslog, LOG_AUTHPRIV, LOG_NOTICE, LOG_PID};
+
+/**
+ * Records a login, or refused credentials, in the system's authentication log.
+ * PAM already does this for the passwords it checks, but not for credentials the server vouches for.
+ */
+pub fn audit(message: &str) {
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    unsafe {
+        openlog(
+            b"simu_suid_helper\0".as_ptr() as *const c_char,
+            LOG_PID,
+            LOG_AUTHPRIV,
+        );
+        // Never as the format, it can contain names from the request
+        syslog(
+            LOG_NOTICE,
+            b"%s\0".as_ptr() as *const c_char,
+            message.as_ptr(),
+        );
+    }
+}
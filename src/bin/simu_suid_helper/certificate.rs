@@ -0,0 +1,56 @@
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/**
+ * What a client certificate can be mapped to an account by.
+ *
+ * The certificate was verified by the server during the TLS handshake,
+ * so it is only parsed here, not checked again.
+ */
+pub struct CertificateNames {
+    /// The whole subject, for the audit log
+    pub subject: String,
+    pub common_names: Vec<String>,
+    /// DNS names, email addresses and URIs among the subject alternative names
+    pub sans: Vec<String>,
+}
+
+impl CertificateNames {
+    pub fn parse(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let common_names = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(str::to_owned)
+            .collect();
+        // A malformed or repeated extension makes the names ambiguous
+        let sans = match cert.subject_alternative_name().ok()? {
+            Some(ext) => ext
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some((*name).to_owned()),
+                    _ => None,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        Some(Self {
+            subject: cert.subject().to_string(),
+            common_names,
+            sans,
+        })
+    }
+
+    pub fn common_names(&self) -> Vec<&str> {
+        self.common_names.iter().map(String::as_str).collect()
+    }
+
+    pub fn sans(&self) -> Vec<&str> {
+        self.sans.iter().map(String::as_str).collect()
+    }
+}
@@ -0,0 +1,98 @@
+use libc::{getrlimit, rlim_t, rlimit, setrlimit};
+use simu::config::Limits;
+
+/// Seconds of CPU time for everything done as root, PAM included
+const ROOT_CPU_TIME: rlim_t = 60;
+/// Bytes of virtual memory for everything done as root
+const ROOT_ADDRESS_SPACE: rlim_t = 1 << 30;
+
+/**
+ * The limits we were started with, put back by `apply_limits` where none are configured.
+ */
+pub struct Inherited {
+    cpu_time: rlimit,
+    address_space: rlimit,
+}
+
+/**
+ * Bounds what is done as root before switching to the user, such as running PAM modules
+ * or managing tokens and links, whatever the configuration says.
+ * Only the soft limits are lowered, so the configured limits can still be applied afterwards.
+ */
+pub fn limit_root() -> Inherited {
+    let mut inherited = Inherited {
+        cpu_time: unlimited(),
+        address_space: unlimited(),
+    };
+    let resources = [
+        (libc::RLIMIT_CPU, ROOT_CPU_TIME, &mut inherited.cpu_time),
+        (
+            libc::RLIMIT_AS,
+            ROOT_ADDRESS_SPACE,
+            &mut inherited.address_space,
+        ),
+    ];
+    for (resource, value, current) in resources {
+        let limit = unsafe {
+            if getrlimit(resource, current) < 0 {
+                panic!("Could not get resource limits");
+            }
+            rlimit {
+                rlim_cur: value.min(current.rlim_cur),
+                rlim_max: current.rlim_max,
+            }
+        };
+        if unsafe { setrlimit(resource, &limit) } < 0 {
+            panic!("Could not set resource limits");
+        }
+    }
+    inherited
+}
+
+/**
+ * Applies the configured limits, after switching to the user so they can't be raised again.
+ * A limit above what we inherited is lowered to the inherited one, as it can't be raised either.
+ */
+pub fn apply_limits(limits: &Limits, inherited: &Inherited) {
+    let resources = [
+        (libc::RLIMIT_CPU, limits.cpu_time, Some(inherited.cpu_time)),
+        (
+            libc::RLIMIT_AS,
+            limits.address_space,
+            Some(inherited.address_space),
+        ),
+        (libc::RLIMIT_NOFILE, limits.open_files, None),
+        (libc::RLIMIT_FSIZE, limits.file_size, None),
+        (libc::RLIMIT_NPROC, limits.processes, None),
+    ];
+    for (resource, value, inherited) in resources {
+        let limit = match (value, inherited) {
+            (Some(value), _) => {
+                let mut current = unlimited();
+                let value = unsafe {
+                    if getrlimit(resource, &mut current) < 0 {
+                        panic!("Could not get resource limits");
+                    }
+                    (value as rlim_t).min(current.rlim_max)
+                };
+                rlimit {
+                    rlim_cur: value,
+                    rlim_max: value,
+                }
+            }
+            // Lowered by `limit_root` only, so what the server had is left when none is configured
+            (None, Some(inherited)) => inherited,
+            (None, None) => continue,
+        };
+        if unsafe { setrlimit(resource, &limit) } < 0 {
+            panic!("Could not set resource limits");
+        }
+    }
+}
+
+fn unlimited() -> rlimit {
+    rlimit {
+        rlim_cur: libc::RLIM_INFINITY,
+        rlim_max: libc::RLIM_INFINITY,
+    }
+}
@@ -0,0 +1,341 @@
+use std::fs::File;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+
+use libc::uid_t;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use simu::config::Config;
+use simu::secret::Secret;
+use simu::{DropBox, LinkInfo, LinkOperation, NewLink, Operation, ReturnCode};
+
+use crate::state::{self, now};
+use crate::upload::{extension_of, sanitize_name};
+
+/// Keeps a single user from filling the disk with links
+const MAX_LINKS_PER_USER: usize = 100;
+const SALT_LEN: usize = 16;
+/// Link passwords are chosen by people, so they are hashed slowly
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/**
+ * A shared link as kept on disk, in a file named by its id.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+    pub uid: uid_t,
+    password: Option<PasswordHash>,
+    pub info: LinkInfo,
+}
+
+/**
+ * PBKDF2-HMAC-SHA256 of a link's password, hex encoded.
+ */
+#[derive(Serialize, Deserialize)]
+struct PasswordHash {
+    salt: String,
+    hash: String,
+}
+
+impl PasswordHash {
+    fn new(password: &Secret) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut hash = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations(),
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+        Self {
+            salt: hex::encode(salt),
+            hash: hex::encode(hash),
+        }
+    }
+
+    fn verify(&self, password: &Secret) -> bool {
+        match (hex::decode(&self.salt), hex::decode(&self.hash)) {
+            // Constant time
+            (Ok(salt), Ok(hash)) => pbkdf2::verify(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                iterations(),
+                &salt,
+                password.as_bytes(),
+                &hash,
+            )
+            .is_ok(),
+            _ => false,
+        }
+    }
+}
+
+fn iterations() -> NonZeroU32 {
+    NonZeroU32::new(PBKDF2_ITERATIONS).unwrap()
+}
+
+fn dir() -> PathBuf {
+    state::dir("links")
+}
+
+fn load(id: &str) -> Option<Record> {
+    state::load(&dir(), id)
+}
+
+fn store(record: &Record) -> std::io::Result<()> {
+    state::store(&dir(), &record.info.id, record)
+}
+
+fn records_of(uid: uid_t) -> Vec<Record> {
+    // Uses are counted in place, see `PendingUse`
+    let _lock = state::lock(&dir());
+    let mut records: Vec<Record> = state::load_all(&dir())
+        .into_iter()
+        .filter(|record: &Record| record.uid == uid)
+        .collect();
+    records.sort_by_key(|record| record.info.created);
+    records
+}
+
+fn usable(record: &Record) -> bool {
+    let info = &record.info;
+    let exhausted = match &info.drop_box {
+        Some(drop_box) => drop_box.max_files.map(|max| info.uploads >= max),
+        None => info.max_downloads.map(|max| info.downloads >= max),
+    };
+    info.expires > now() && !exhausted.unwrap_or(false)
+}
+
+/**
+ * Finds the record of a link that can still be visited.
+ * Links that don't exist, expired or ran out of uses are not found,
+ * a missing or wrong password fails the login so the visitor can be asked for it.
+ */
+pub fn open(id: &str, password: Option<&Secret>) -> Result<Record, ReturnCode> {
+    // Not held while checking the password, which takes a while
+    let record = {
+        let _lock = state::lock(&dir());
+        load(id)
+    };
+    let record = match record {
+        Some(record) if usable(&record) => record,
+        _ => return Err(ReturnCode::FileNotFound),
+    };
+    match (&record.password, password) {
+        (None, _) => Ok(record),
+        (Some(hash), Some(password)) if hash.verify(password) => Ok(record),
+        _ => Err(ReturnCode::LoginFailed),
+    }
+}
+
+/**
+ * Whether the link can be used for the operation.
+ * Drop boxes only take uploads, other links only let what they point to be read.
+ */
+pub fn allows(record: &Record, operation: &Operation) -> bool {
+    match record.info.drop_box {
+        Some(_) => matches!(operation, Operation::Upload { .. }),
+        None => operation.is_read(),
+    }
+}
+
+/**
+ * The path within the share an upload to a drop box is stored at,
+ * `name` being what the uploader called their file.
+ */
+pub fn drop_path(record: &Record, name: &[u8]) -> Result<Vec<u8>, ReturnCode> {
+    let drop_box = record
+        .info
+        .drop_box
+        .as_ref()
+        .ok_or(ReturnCode::AccessDenied)?;
+    let name = sanitize_name(name);
+    if !drop_box.extensions.is_empty() {
+        let accepted = extension_of(&name)
+            .map(|extension| drop_box.extensions.contains(&extension))
+            .unwrap_or(false);
+        if !accepted {
+            return Err(ReturnCode::InvalidInput);
+        }
+    }
+    let mut path = record.info.path.trim_matches('/').as_bytes().to_vec();
+    if !path.is_empty() {
+        path.push(b'/');
+    }
+    path.extend_from_slice(&name);
+    Ok(path)
+}
+
+/**
+ * The path within the share a request to the link is for.
+ *
+ * Requests name the linked file or directory itself and what is below it,
+ * relative to the directory it is in, so served files keep their names.
+ */
+pub fn resolve(record: &Record, path: &[u8]) -> Result<Vec<u8>, ReturnCode> {
+    let path = path.strip_prefix(b"/").unwrap_or(path);
+    // Can't climb out of the linked directory, links are left to the share's symlink policy
+    if path.split(|b| *b == b'/').any(|part| part == b"..") {
+        return Err(ReturnCode::FileNotFound);
+    }
+    let linked = record.info.path.trim_matches('/');
+    if linked.is_empty() {
+        return Ok(path.to_vec());
+    }
+    let (parent, name) = linked.rsplit_once('/').unwrap_or(("", linked));
+    let within = match path.strip_prefix(name.as_bytes()) {
+        Some(rest) => rest.is_empty() || rest.starts_with(b"/"),
+        None => false,
+    };
+    if !within {
+        return Err(ReturnCode::FileNotFound);
+    }
+    let mut resolved = parent.as_bytes().to_vec();
+    if !resolved.is_empty() {
+        resolved.push(b'/');
+    }
+    resolved.extend_from_slice(path);
+    Ok(resolved)
+}
+
+/**
+ * A download or upload to be counted against the link's limit once it has succeeded,
+ * so requests that fail don't use the link up.
+ *
+ * Counted after switching to the user, when the records can't be opened anymore,
+ * so the record and its lock are opened beforehand and the record is changed in place.
+ */
+pub struct PendingUse {
+    lock: File,
+    record: File,
+}
+
+impl PendingUse {
+    pub fn new(id: &str) -> Result<Self, ReturnCode> {
+        let dir = dir();
+        Ok(Self {
+            lock: state::lock_file(&dir),
+            record: state::open(&dir, id).ok_or(ReturnCode::FileNotFound)?,
+        })
+    }
+
+    /**
+     * Counts the use, failing if the link has run out of uses or been revoked since it was opened.
+     */
+    pub fn count(mut self) -> Result<(), ReturnCode> {
+        // Only held while counting, so concurrent visits can't both take the last use
+        // but a slow transfer doesn't keep everyone else waiting
+        state::acquire(&self.lock);
+        let mut record: Record = match state::read(&mut self.record) {
+            Some(record) if usable(&record) => record,
+            _ => return Err(ReturnCode::FileNotFound),
+        };
+        match record.info.drop_box {
+            Some(_) => record.info.uploads += 1,
+            None => record.info.downloads += 1,
+        }
+        if let Err(e) = state::rewrite(&mut self.record, &record) {
+            panic!("Cannot store link: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Performs a link operation for the user, returning its output.
+ */
+pub fn run(operation: &LinkOperation, uid: uid_t, config: &Config) -> Result<Vec<u8>, ReturnCode> {
+    match operation {
+        LinkOperation::Create(new) => {
+            create(new, uid, config).map(|info| bincode::serialize(&info).unwrap())
+        }
+        LinkOperation::List => {
+            let infos: Vec<LinkInfo> = records_of(uid)
+                .into_iter()
+                .map(|record| record.info)
+                .collect();
+            Ok(bincode::serialize(&infos).unwrap())
+        }
+        LinkOperation::Revoke(id) => {
+            // So a use isn't counted into a record that is going away
+            let _lock = state::lock(&dir());
+            match load(id) {
+                Some(record) if record.uid == uid => match state::remove(&dir(), id) {
+                    Ok(()) => Ok(Vec::new()),
+                    Err(e) => panic!("Cannot remove link: {}", e),
+                },
+                // Someone else's links are none of their business
+                _ => Err(ReturnCode::FileNotFound),
+            }
+        }
+    }
+}
+
+fn create(new: &NewLink, uid: uid_t, config: &Config) -> Result<LinkInfo, ReturnCode> {
+    if config.share(&new.share).is_none() {
+        return Err(ReturnCode::FileNotFound);
+    }
+    let climbs = new.path.split('/').any(|part| part == "..");
+    if climbs || new.path.contains('\0') || new.expires <= now() {
+        return Err(ReturnCode::InvalidInput);
+    }
+    if new.password.as_ref().map(|p| p.as_bytes().is_empty()) == Some(true) {
+        return Err(ReturnCode::InvalidInput);
+    }
+    let drop_box = new.drop_box.as_ref().map(drop_box).transpose()?;
+    if drop_box.is_some() && new.max_downloads.is_some() {
+        return Err(ReturnCode::InvalidInput);
+    }
+    if records_of(uid).len() >= MAX_LINKS_PER_USER {
+        return Err(ReturnCode::PermissionDenied);
+    }
+    let info = LinkInfo {
+        id: state::new_id(),
+        share: new.share.clone(),
+        path: new.path.clone(),
+        created: now(),
+        expires: new.expires,
+        max_downloads: new.max_downloads,
+        downloads: 0,
+        has_password: new.password.is_some(),
+        uploads: 0,
+        drop_box,
+    };
+    let record = Record {
+        uid,
+        password: new.password.as_ref().map(PasswordHash::new),
+        info: info.clone(),
+    };
+    if let Err(e) = store(&record) {
+        panic!("Cannot store link: {}", e);
+    }
+    Ok(info)
+}
+
+/**
+ * Checks what a new drop box is to accept, normalizing its extensions to how `extension_of` gives them.
+ */
+fn drop_box(new: &DropBox) -> Result<DropBox, ReturnCode> {
+    if new.max_file_size == 0 {
+        return Err(ReturnCode::InvalidInput);
+    }
+    let extensions = new
+        .extensions
+        .iter()
+        .map(|extension| {
+            let extension = extension.trim_start_matches('.').to_lowercase();
+            if extension.is_empty() || !extension.chars().all(char::is_alphanumeric) {
+                return Err(ReturnCode::InvalidInput);
+            }
+            Ok(extension)
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(DropBox {
+        max_file_size: new.max_file_size,
+        extensions,
+        max_files: new.max_files,
+    })
+}
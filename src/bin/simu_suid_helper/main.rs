@@ -0,0 +1,1029 @@
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{DirBuilder, Metadata, OpenOptions};
+use std::io::{stdin, stdout, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use libc::{
+    _exit, alarm, c_char, c_int, c_long, c_uint, chmod, fchownat, fcntl, getppid, getpwnam, gid_t,
+    initgroups, mode_t, pid_t, prctl, seteuid, setgid, setgroups, setreuid, setuid, sighandler_t,
+    signal, time_t, timespec, uid_t, umask, unlink, utimensat, AT_EMPTY_PATH, AT_FDCWD,
+    AT_SYMLINK_NOFOLLOW, ELOOP, F_GETFL, F_SETFL, O_NOFOLLOW, O_NONBLOCK, O_PATH, PR_SET_DUMPABLE,
+    PR_SET_PDEATHSIG, SIGALRM, SIGKILL, UTIME_OMIT,
+};
+use pam_sys::PamReturnCode;
+use simu::config::{ClientCertificates, Config, Share, SymlinkPolicy};
+use simu::secret::Secret;
+use simu::{
+    Attributes, Credentials, Directory, DirectoryEntry, HelperRequest, Operation, PasswordChange,
+    ReturnCode, SignedRequest,
+};
+
+mod audit;
+mod certificate;
+mod limits;
+mod links;
+mod pam;
+mod permissions;
+mod policy;
+mod resolve;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod sshsig;
+mod state;
+mod tokens;
+mod upload;
+
+use crate::audit::audit;
+use crate::certificate::CertificateNames;
+use crate::limits::{apply_limits, limit_root};
+use crate::links::PendingUse;
+use crate::pam::Pam;
+use crate::permissions::DirPermissions;
+use crate::policy::{
+    check_access, check_anonymous, creation_group, group_id, started_by_server, uid_of, username_of,
+};
+use crate::resolve::{io_error_code, link_allowed, resolve};
+
+const PAM_SERVICE: &str = "login";
+const BUF_SIZE: usize = 4096;
+/// The group owning no files, first of these that exists, as Debian and Fedora call it differently
+const ANONYMOUS_GROUPS: [&str; 2] = ["nogroup", "nobody"];
+
+/// A file being uploaded, removed if we time out before it is complete
+static INCOMPLETE: AtomicPtr<c_char> = AtomicPtr::new(std::ptr::null_mut());
+
+/**
+ * How a user proves who they are, beyond naming an account.
+ */
+enum Proof {
+    Password(Secret),
+    /// Already checked, by the server or against our own token store
+    Verified,
+    SshSignature(SignedRequest, Vec<u8>),
+    /// Nothing at all, served as the share's unprivileged account without asking PAM
+    Anonymous,
+}
+
+fn main() {
+    // The token and link stores are root's, in case Linux capabilities are used instead of the SUID
+    // bit. Only the effective uid, as the real one tells who started us, and before
+    // `die_with_parent` and `make_undumpable`, as changing credentials resets what they set up.
+    if unsafe { seteuid(0) } < 0 {
+        panic!("SUID helper binary not SUID or setcap cap_setuid,cap_setgid+ep!");
+    }
+    let parent = unsafe { getppid() };
+    die_with_parent(parent);
+    make_undumpable();
+    let inherited_limits = limit_root();
+
+    let request = HelperRequest::read_from(&mut stdin().lock());
+    if request.is_none() {
+        // no stdin reading happened
+        panic!("Can't read input");
+    }
+    let mut request = request.unwrap();
+    // The body of an upload follows on stdin, which is read along with it
+    if !matches!(request.operation, Operation::Upload { .. }) {
+        watch_for_cancellation();
+    }
+    set_up_timeouts();
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => panic!("Invalid configuration: {}", e),
+    };
+    // A link stands for a path of its creator's, which the request is relative to
+    let link = match &request.credentials {
+        Credentials::Link { id, password, .. } => {
+            let record = links::open(id, password.as_ref()).unwrap_or_else(|code| fail(code));
+            if !links::allows(&record, &request.operation) {
+                access_denied();
+            }
+            request.path = match request.operation {
+                Operation::Upload { .. } => links::drop_path(&record, &request.path),
+                _ => links::resolve(&record, &request.path),
+            }
+            .unwrap_or_else(|code| fail(code));
+            request.share = record.info.share.clone();
+            Some(record)
+        }
+        _ => None,
+    };
+    // Counted once the file has been opened or stored, but opened now as the store is out of reach of the user
+    let pending_use = match (&link, &request.credentials) {
+        (Some(record), Credentials::Link { counted: true, .. })
+            if matches!(
+                request.operation,
+                Operation::ReadFile | Operation::Upload { .. }
+            ) =>
+        {
+            Some(PendingUse::new(&record.info.id).unwrap_or_else(|code| fail(code)))
+        }
+        _ => None,
+    };
+    // Managing the account isn't tied to a share
+    let share = match &request.operation {
+        Operation::Tokens(_)
+        | Operation::ChangePassword(_)
+        | Operation::Links(_)
+        | Operation::Login => None,
+        _ => match config.share(&request.share) {
+            Some(share) => Some(share),
+            None => file_not_found(),
+        },
+    };
+    // Counted as used once the login succeeds
+    let mut used_token = None;
+    let (username, proof, method) = match request.credentials {
+        Credentials::Password { username, password } => match CString::new(username) {
+            Ok(username) if password.as_c_str().is_some() => {
+                (username, Proof::Password(password), "a password")
+            }
+            _ => login_failed(), // PAM can't take these, so they can't be valid either
+        },
+        Credentials::ClientCertificate(der) => {
+            if !share
+                .map(|share| share.client_certificates)
+                .unwrap_or(false)
+            {
+                access_denied();
+            }
+            if !started_by_server(&config.server) {
+                audit("refused a client certificate not passed on by the server");
+                access_denied();
+            }
+            let username = map_certificate(&der);
+            (username, Proof::Verified, "a client certificate")
+        }
+        Credentials::RemoteUser(username) => {
+            if !started_by_server(&config.server) {
+                audit("refused a remote user not passed on by the server");
+                access_denied();
+            }
+            match CString::new(username) {
+                Ok(username) => (username, Proof::Verified, "a header from a trusted proxy"),
+                Err(_) => login_failed(),
+            }
+        }
+        Credentials::Token(token) => {
+            let record = match tokens::authenticate(&token) {
+                Some(record) => record,
+                None => login_failed(),
+            };
+            let scope = &record.info.scope;
+            if let Err(code) =
+                tokens::check_scope(scope, &request.share, &request.path, &request.operation)
+            {
+                fail(code);
+            }
+            let username = match username_of(record.uid) {
+                Some(username) => username,
+                None => login_failed(),
+            };
+            used_token = Some(record.info.id);
+            (username, Proof::Verified, "an API token")
+        }
+        Credentials::Link { .. } => {
+            let record = link.as_ref().expect("links are opened first");
+            match username_of(record.uid) {
+                Some(username) => (username, Proof::Verified, "a shared link"),
+                None => file_not_found(),
+            }
+        }
+        Credentials::Anonymous => {
+            let account = share
+                .and_then(|share| share.anonymous.as_deref())
+                .unwrap_or_else(|| login_failed());
+            if !request.operation.is_read() {
+                access_denied();
+            }
+            match CString::new(account) {
+                Ok(account) => (account, Proof::Anonymous, "no credentials"),
+                Err(_) => access_denied(),
+            }
+        }
+        Credentials::SshSignature {
+            username,
+            request: signed,
+            signature,
+        } => {
+            // Checked before the signature itself, which needs root to read authorized_keys
+            if !signed.is_fresh()
+                || !sshsig::signs(
+                    &signed,
+                    &config,
+                    &request.share,
+                    &request.path,
+                    &request.operation,
+                )
+            {
+                login_failed();
+            }
+            match CString::new(username) {
+                Ok(username) => (
+                    username,
+                    Proof::SshSignature(signed, signature),
+                    "an SSH signature",
+                ),
+                Err(_) => login_failed(),
+            }
+        }
+    };
+
+    // Refuse to even attempt authenticating accounts outside the policy,
+    // which doesn't apply to the account of anonymous shares, as no one logs in to it
+    let anonymous = matches!(proof, Proof::Anonymous);
+    let access = if anonymous {
+        check_anonymous(&username)
+    } else {
+        check_access(&config.access, &username)
+    };
+    match (access, &proof) {
+        (Ok(()), _) => {}
+        // Denied without knowing who is asking, so it mustn't tell which accounts exist
+        (Err(_), Proof::Password(_) | Proof::SshSignature(..)) => login_failed(),
+        (Err(code), _) => fail(code),
+    }
+
+    unsafe {
+        let res = setuid(0); // Wholly root before calling PAM, which may look at the real uid
+        if res < 0 {
+            panic!("Could not become root");
+        }
+    }
+
+    //eprintln!("We wish to become '{}', so i can read file '{}'", username.to_string_lossy(), path.to_string_lossy());
+    arm_timeout(request.timeouts.auth);
+    if let Operation::ChangePassword(new_password) = &request.operation {
+        // Only the current password will do, any other way of logging in could have been stolen
+        let password = match &proof {
+            Proof::Password(password) => password,
+            _ => access_denied(),
+        };
+        if new_password.as_bytes().is_empty() || new_password.as_c_str().is_none() {
+            invalid_input();
+        }
+        match change_password(&username, password, new_password) {
+            Ok(result) => {
+                audit(&format!(
+                    "{:?} {} their password",
+                    username,
+                    if result.changed {
+                        "changed"
+                    } else {
+                        "failed to change"
+                    }
+                ));
+                write_output(&bincode::serialize(&result).unwrap());
+            }
+            Err(e) => panic!("PAM failed: {}", e),
+        }
+        return;
+    }
+    let res = match &proof {
+        Proof::Password(password) => test_auth(&username, password),
+        Proof::Verified => test_account(&username),
+        Proof::SshSignature(signed, signature) => {
+            if !sshsig::verify(&username, signed, signature) {
+                login_failed();
+            }
+            test_account(&username)
+        }
+        Proof::Anonymous => Ok(1),
+    };
+    if let Err(e) = res {
+        panic!("PAM failed: {}", e);
+    }
+    if res.unwrap() == 0 {
+        login_failed();
+    }
+    drop(proof);
+    if let Some(id) = used_token {
+        tokens::touch(&id);
+    }
+    // Anonymous visits aren't logins, and would drown out those that are
+    if !anonymous {
+        audit(&format!(
+            "{:?} logged in with {} {}",
+            username,
+            method,
+            match share {
+                Some(share) => format!("to share {:?}", share.name),
+                None if matches!(request.operation, Operation::Login) => {
+                    "to list the shares".to_string()
+                }
+                None => "to manage their account".to_string(),
+            }
+        ));
+    }
+
+    let share = match (share, &request.operation) {
+        (Some(share), _) => share,
+        (None, Operation::Tokens(operation)) => {
+            // The store is root's, so this is done before switching users
+            let uid = uid_of(&username).unwrap_or_else(|| login_failed());
+            match tokens::run(operation, uid) {
+                Ok(output) => write_output(&output),
+                Err(code) => fail(code),
+            }
+            return;
+        }
+        (None, Operation::Links(operation)) => {
+            let uid = uid_of(&username).unwrap_or_else(|| login_failed());
+            match links::run(operation, uid, &config) {
+                Ok(output) => write_output(&output),
+                Err(code) => fail(code),
+            }
+            return;
+        }
+        (None, Operation::Login) => return,
+        (None, _) => file_not_found(),
+    };
+    // Anonymous visitors don't get the groups of the account, so only what anyone may read is served
+    let ret = become_user(&username, anonymous);
+    if ret < 0 {
+        panic!("Could not switch user");
+    }
+    // Changing credentials resets the parent death signal and dumpability.
+    // Uploads aren't killed outright, so they can remove what they stored.
+    // The server going away ends their body, and they time out if its thread does.
+    if !matches!(request.operation, Operation::Upload { .. }) {
+        die_with_parent(parent);
+    }
+    make_undumpable();
+    apply_limits(&config.limits, &inherited_limits);
+    if let Some(mask) = share.umask {
+        unsafe {
+            umask(mask as mode_t & 0o777);
+        }
+    }
+    // Looked up before the sandbox, which keeps us from reading the group database
+    let group = match &request.operation {
+        Operation::Upload { group } | Operation::MakeDir { group } => {
+            creation_group(share, group.as_deref()).unwrap_or_else(|code| fail(code))
+        }
+        // Whether the user may have it is left to the kernel
+        Operation::SetAttributes(attributes) => attributes
+            .group
+            .as_deref()
+            .map(|name| group_id(name).unwrap_or_else(|| invalid_input())),
+        _ => None,
+    };
+
+    arm_timeout(request.timeouts.first_byte);
+    // What is being created doesn't exist yet, the directory it goes in is resolved instead
+    let (resolved, name) = match request.operation {
+        Operation::Upload { .. } | Operation::MakeDir { .. } => {
+            let path = request.path.strip_suffix(b"/").unwrap_or(&request.path);
+            let (dir, name) = match path.iter().rposition(|b| *b == b'/') {
+                Some(slash) => (&path[..slash], &path[slash + 1..]),
+                None => (&b""[..], path),
+            };
+            if matches!(name, b"" | b"." | b"..") {
+                invalid_input();
+            }
+            (dir, name)
+        }
+        _ => (&request.path[..], &b""[..]),
+    };
+    let path = match resolve(share, Path::new(OsStr::from_bytes(resolved))) {
+        Ok(path) => path,
+        Err(code) => fail(code),
+    };
+    #[cfg(target_os = "linux")]
+    {
+        // Picked up by the server, which logs it
+        eprintln!(
+            "sandbox: {}",
+            sandbox::restrict(share, &path, &request.operation)
+        );
+    }
+    match request.operation {
+        Operation::ReadDir => read_dir_to_stdout(share, &path),
+        Operation::ReadFile => read_file_to_stdout(
+            &path,
+            share.symlinks == SymlinkPolicy::Never,
+            request.timeouts.idle,
+            pending_use,
+        ),
+        Operation::Upload { .. } => {
+            let drop_box = link
+                .as_ref()
+                .and_then(|record| record.info.drop_box.as_ref());
+            // Drop boxes never replace what was dropped before
+            let result = upload::receive(
+                &path,
+                name,
+                drop_box.is_none(),
+                group,
+                drop_box.map(|drop_box| drop_box.max_file_size),
+                request.timeouts.idle,
+            );
+            match result {
+                Ok(mut uploaded) => {
+                    if let Some(pending_use) = pending_use {
+                        if let Err(code) = pending_use.count() {
+                            // Filled up by others meanwhile
+                            let _ =
+                                std::fs::remove_file(path.join(OsStr::from_bytes(&uploaded.name)));
+                            fail(code);
+                        }
+                    }
+                    if drop_box.is_some() {
+                        audit(&format!(
+                            "{:?} received {:?} through a drop box",
+                            username,
+                            String::from_utf8_lossy(&uploaded.name)
+                        ));
+                        // What else was dropped, and so renamed around, is none of the uploader's business
+                        uploaded.name = name.to_vec();
+                    }
+                    write_output(&bincode::serialize(&uploaded).unwrap());
+                }
+                Err(code) => fail(code),
+            }
+        }
+        Operation::MakeDir { .. } => make_dir(&path.join(OsStr::from_bytes(name)), group),
+        Operation::SetAttributes(attributes) => set_attributes(
+            &path,
+            &attributes,
+            group,
+            share.symlinks != SymlinkPolicy::Follow,
+        ),
+        Operation::Tokens(_)
+        | Operation::ChangePassword(_)
+        | Operation::Links(_)
+        | Operation::Login => {
+            unreachable!("account operations are done before switching users")
+        }
+    }
+}
+
+/**
+ * Asks the kernel to kill us if the server goes away.
+ *
+ * Note that the "parent" here is the thread which spawned us, the server's worker thread
+ * that handled the request. Workers live as long as the server, so in practice this
+ * fires when the server exits or a worker is restarted, not when a request ends.
+ */
+fn die_with_parent(parent: pid_t) {
+    unsafe {
+        prctl(PR_SET_PDEATHSIG, SIGKILL);
+        if getppid() != parent {
+            // Parent died before the signal was set up
+            cancelled();
+        }
+    }
+}
+
+/**
+ * Keeps credentials in our memory out of core dumps, and keeps the user
+ * we switch to from attaching to us with ptrace.
+ */
+fn make_undumpable() {
+    unsafe {
+        prctl(PR_SET_DUMPABLE, 0);
+    }
+}
+
+/**
+ * The server keeps our stdin open while someone is interested in the result,
+ * as it cannot signal us after we have switched users.
+ * Once it closes, we stop whatever we are doing.
+ */
+fn watch_for_cancellation() {
+    std::thread::spawn(|| {
+        let mut buf = [0; 64];
+        loop {
+            match stdin().read(&mut buf) {
+                Ok(0) => cancelled(),
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => cancelled(),
+            }
+        }
+    });
+}
+
+extern "C" fn timed_out(_signal: c_int) {
+    // Only async-signal-safe calls allowed here
+    unsafe {
+        let incomplete = INCOMPLETE.load(Ordering::SeqCst);
+        if !incomplete.is_null() {
+            unlink(incomplete);
+        }
+        _exit(ReturnCode::Timeout as i32)
+    }
+}
+
+/**
+ * Sets the file removed if we time out, replacing the previous one, None once nothing is to be removed.
+ */
+fn remove_on_timeout(path: Option<&Path>) {
+    let path = path
+        .and_then(|path| CString::new(path.as_os_str().as_bytes()).ok())
+        .map_or(std::ptr::null_mut(), CString::into_raw);
+    let previous = INCOMPLETE.swap(path, Ordering::SeqCst);
+    if !previous.is_null() {
+        drop(unsafe { CString::from_raw(previous) });
+    }
+}
+
+fn set_up_timeouts() {
+    unsafe {
+        signal(SIGALRM, timed_out as extern "C" fn(c_int) as sighandler_t);
+    }
+}
+
+/**
+ * Limits how long the current phase can take, replacing the previous limit.
+ * Blocking calls such as opening a FIFO or a hung NFS mount get cut short too.
+ */
+fn arm_timeout(secs: u64) {
+    unsafe {
+        alarm(secs.try_into().unwrap_or(c_uint::MAX));
+    }
+}
+
+/**
+ * Runs user detail through PAM, returns either PAM interaction errors, or Ok(0) on bad auth, or Ok(1) on good auth.
+ * A right password that has expired ends the helper, so the user can be asked to change it.
+ */
+fn test_auth(username: &CStr, password: &Secret) -> Result<i32, PamReturnCode> {
+    let mut pam = Pam::start(PAM_SERVICE, username, Some(password))?;
+    match pam.authenticate() {
+        Ok(()) => Ok(1),
+        Err(PamReturnCode::NEW_AUTHTOK_REQD) => password_expired(),
+        // Not an 'error' but failed authentication
+        Err(_) => Ok(0),
+    }
+}
+
+/**
+ * Logs in with the current password, then changes it with PAM, expired or not.
+ * Failing to log in ends the helper, a new password PAM refuses is reported along with its reasons.
+ */
+fn change_password(
+    username: &CStr,
+    password: &Secret,
+    new_password: &Secret,
+) -> Result<PasswordChange, PamReturnCode> {
+    let mut pam = Pam::start(PAM_SERVICE, username, Some(password))?;
+    let expired = match pam.authenticate() {
+        Ok(()) => false,
+        Err(PamReturnCode::NEW_AUTHTOK_REQD) => true,
+        Err(_) => login_failed(),
+    };
+    // Like passwd, act for the user while staying able to update the password database.
+    // Otherwise modules take us for root, who may set any password without giving the current one.
+    let uid = uid_of(username).unwrap_or_else(|| login_failed());
+    if unsafe { setreuid(uid, 0) } < 0 {
+        panic!("Could not set the real user!");
+    }
+    let changed = pam.change_password(new_password, expired).is_ok();
+    Ok(PasswordChange {
+        changed,
+        messages: pam.messages(),
+    })
+}
+
+/**
+ * Like `test_auth`, for users authenticated by other means, so only their account is checked.
+ */
+fn test_account(username: &CStr) -> Result<i32, PamReturnCode> {
+    let mut pam = Pam::start(PAM_SERVICE, username, None)?;
+    if pam.account().is_err() {
+        return Ok(0);
+    }
+    Ok(1)
+}
+
+/**
+ * Finds the account a client certificate logs in as.
+ */
+fn map_certificate(der: &[u8]) -> CString {
+    let names = match CertificateNames::parse(der) {
+        Some(names) => names,
+        None => login_failed(),
+    };
+    let mappings = match ClientCertificates::load() {
+        Ok(mappings) => mappings,
+        Err(e) => panic!("Invalid configuration: {}", e),
+    };
+    match mappings
+        .user_for(&names.common_names(), &names.sans())
+        .map(CString::new)
+    {
+        Some(Ok(username)) => username,
+        _ => {
+            audit(&format!(
+                "refused client certificate {:?}, it is not mapped to an account",
+                names.subject
+            ));
+            login_failed()
+        }
+    }
+}
+
+/**
+ * This has been developed and tested for Linux, but in
+ * theory should also work on BSDs and SVr4-compat
+ * systems (only `initgroups` is not POSIX).
+ *
+ * username: system username to become
+ * anonymous: whether to serve anonymous visitors, who get none of the account's groups
+ */
+fn become_user(username: &CStr, anonymous: bool) -> i32 {
+    let pwent = unsafe { getpwnam(username.as_ptr()) };
+    if pwent.is_null() {
+        return -1; // pwent is generally static memory, if this fails, you have some really crazy issues and this is the least of your concern
+    }
+    // Not even the primary group, which files may well be shared with
+    let gid = if anonymous {
+        match ANONYMOUS_GROUPS.iter().find_map(|name| group_id(name)) {
+            Some(gid) => gid,
+            None => panic!(
+                "Neither a nogroup nor a nobody group exists to serve anonymous visitors with"
+            ),
+        }
+    } else {
+        unsafe { (*pwent).pw_gid }
+    };
+    let uid = unsafe { (*pwent).pw_uid };
+
+    unsafe {
+        // This setgid is needed even though we are using setgroups,
+        // as otherwise this group is later reinserted to supplemental groups.
+        let ret = setgid(gid);
+        if ret < 0 {
+            //println!("setgid returned {}", errno());
+            return ret;
+        }
+    }
+    //println!("After setgid, im \t'{}'", &get_id()); // uid=1000(keerup) gid=1000(keerup) euid=0(root) groups=1000(keerup),24(cdrom),25(floppy),27(sudo),29(audio),30(dip),44(video),46(plugdev),109(netdev),112(bluetooth),116(scanner),130(libvirt)
+
+    unsafe {
+        // Remove all current supplemental groups
+        let ret = setgroups(0, std::ptr::null());
+        if ret < 0 {
+            //println!("setgroups returned {}", errno());
+            return ret;
+        }
+    }
+    //println!("After setgroups, im \t'{}'", &get_id()); // uid=1000(keerup) gid=30033(keerup_test) euid=0(root) groups=30033(keerup_test)
+
+    if !anonymous {
+        // Add all user's supplemental groups
+        let ret = unsafe { initgroups(username.as_ptr(), gid) };
+        if ret < 0 {
+            //println!("initgroups returned {}", errno());
+            return ret;
+        }
+    }
+    //println!("After initgroups, im \t'{}'", &get_id()); // uid=1000(keerup) gid=30033(keerup_test) euid=0(root) groups=30033(keerup_test),25(floppy),116(scanner),30034(keerup_test2)
+
+    unsafe {
+        // Set to requested user ID
+        let ret = setuid(uid);
+        if ret < 0 {
+            //println!("setuid returned {}", errno());
+            return ret;
+        }
+    }
+    //println!("After setuid, im \t'{}'", &get_id()); // uid=30033(keerup_test) gid=30033(keerup_test) groups=30033(keerup_test),25(floppy),116(scanner),30034(keerup_test2)
+
+    #[cfg(feature = "root-safeguard")]
+    unsafe {
+        // As a safeguard, lets attempt to become root again
+        // This should fail in the common case
+        let ret = setuid(0);
+        if ret == 0 {
+            panic!("We should not be able to become root again!");
+        }
+    }
+
+    // Switch of user complete
+
+    0
+}
+
+/**
+ * Streams the file at the path, counting `pending_use` once it has been opened.
+ */
+fn read_file_to_stdout(
+    path: &Path,
+    nofollow: bool,
+    idle_timeout: u64,
+    pending_use: Option<PendingUse>,
+) {
+    // Check before opening, as opening a device node alone can have side effects
+    match std::fs::metadata(path) {
+        Err(e) => io_error(e),
+        Ok(meta) => check_regular_file(&meta),
+    }
+    // Non-blocking, so a FIFO swapped in after the check can't hang us on open
+    let flags = if nofollow {
+        O_NONBLOCK | O_NOFOLLOW
+    } else {
+        O_NONBLOCK
+    };
+    let mut file = match OpenOptions::new().read(true).custom_flags(flags).open(path) {
+        Err(e) => io_error(e),
+        Ok(f) => f,
+    };
+    match file.metadata() {
+        Err(e) => io_error(e),
+        Ok(meta) => check_regular_file(&meta),
+    }
+    if let Some(pending_use) = pending_use {
+        pending_use.count().unwrap_or_else(|code| fail(code));
+    }
+    unsafe {
+        let flags = fcntl(file.as_raw_fd(), F_GETFL);
+        fcntl(file.as_raw_fd(), F_SETFL, flags & !O_NONBLOCK);
+    }
+    loop {
+        let mut buf = [0; BUF_SIZE];
+        let count = file.read(&mut buf);
+        match count {
+            Ok(sz) => {
+                if sz == 0 {
+                    return; // EOF
+                }
+                loop {
+                    let res = stdout().write_all(&buf[0..sz]);
+                    if let Err(e) = res {
+                        match e.kind() {
+                            ErrorKind::Interrupted => {
+                                continue;
+                            } // this should be retried, but cannot let repeat outer loop, as that mangles the file
+                            ErrorKind::BrokenPipe => {
+                                cancelled(); // Server stopped listening, client likely lost
+                            }
+                            _ => {
+                                panic!("error while writing to output: {:?}", e.kind());
+                            }
+                        }
+                    } else {
+                        arm_timeout(idle_timeout);
+                        break;
+                    }
+                }
+            }
+            Err(err) => {
+                // read failed, lets check if it's potentially recoverable
+                match err.kind() {
+                    ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    _ => {
+                        panic!("file reading failed! '{:?}'", err.kind())
+                    }
+                }
+            }
+        }
+    }
+}
+
+/**
+ * FIFOs, sockets and device nodes can block or stream forever, so only regular files are served.
+ */
+fn check_regular_file(meta: &Metadata) {
+    if meta.is_dir() {
+        unexpected_type();
+    }
+    if !meta.is_file() {
+        special_file();
+    }
+}
+
+fn io_error(e: std::io::Error) -> ! {
+    match e.raw_os_error() {
+        Some(ELOOP) => symlink_denied(), // O_NOFOLLOW hit a link
+        _ => fail(io_error_code(e.kind())),
+    }
+}
+
+fn read_dir_to_stdout(share: &Share, path: &Path) {
+    let dir_permissions = DirPermissions::new(path);
+    let entries = match std::fs::read_dir(path) {
+        Ok(it) => it
+            .filter_map(|ent| ent.ok())
+            .map(|ent| {
+                let meta = ent.metadata().ok();
+                let is_link = meta
+                    .as_ref()
+                    .map(|meta| meta.file_type().is_symlink())
+                    .unwrap_or(false);
+                let follow_link = match &meta {
+                    Some(meta) => is_link && link_allowed(share, &ent.path(), meta),
+                    None => false,
+                };
+                let permissions =
+                    dir_permissions.entry(&ent.path(), meta.as_ref(), !is_link || follow_link);
+                DirectoryEntry::new(&ent, follow_link, permissions)
+            })
+            .collect(),
+        Err(_) => {
+            // doesnt exist, no perms, or isnt dir
+            file_not_found();
+        }
+    };
+    let dir = Directory {
+        entries,
+        modifiable: dir_permissions.modifiable(),
+    };
+    write_output(&bincode::serialize(&dir).unwrap());
+}
+
+/**
+ * Creates a directory, handing it to `group` if given.
+ */
+fn make_dir(path: &Path, group: Option<gid_t>) {
+    if let Err(e) = DirBuilder::new().mode(0o777).create(path) {
+        io_error(e);
+    }
+    let gid = match group {
+        Some(gid) => gid,
+        None => return,
+    };
+    let c_path = CString::new(path.as_os_str().as_bytes()).unwrap_or_else(|_| invalid_input());
+    // Not following a link someone swapped in since
+    let ret = unsafe {
+        fchownat(
+            AT_FDCWD,
+            c_path.as_ptr(),
+            uid_t::MAX,
+            gid,
+            AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret < 0 {
+        let _ = std::fs::remove_dir(path);
+        permission_denied();
+    }
+}
+
+/**
+ * Changes the group, mode and modification time as asked, in that order,
+ * as giving a file to another group can clear its set-group-ID bit.
+ *
+ * We are the user by now, so the kernel's rules apply: only the owner may change the mode or set the time,
+ * and only to groups they are in.
+ *
+ * Everything is changed through a descriptor opened once, so a link swapped in after resolving
+ * can't redirect any of it. Unless the share follows all links, a link at the path is refused,
+ * whether or not its target is allowed.
+ */
+fn set_attributes(path: &Path, attributes: &Attributes, group: Option<gid_t>, nofollow: bool) {
+    if attributes.mode.map(|mode| mode > 0o7777).unwrap_or(false)
+        || attributes
+            .modified
+            .map(|(_, nanos)| nanos >= 1_000_000_000)
+            .unwrap_or(false)
+    {
+        invalid_input();
+    }
+    // Only names what is at the path, which works for files the user can't read too
+    let flags = if nofollow {
+        O_PATH | O_NOFOLLOW
+    } else {
+        O_PATH
+    };
+    let file = match OpenOptions::new().read(true).custom_flags(flags).open(path) {
+        Err(e) => io_error(e),
+        Ok(f) => f,
+    };
+    match file.metadata() {
+        Err(e) => io_error(e),
+        Ok(meta) if meta.file_type().is_symlink() => symlink_denied(),
+        Ok(_) => {}
+    }
+    // Descriptors opened with O_PATH can't be changed directly, but the kernel resolves
+    // their entry in /proc to exactly what was opened
+    let fd_path = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap();
+    let check = |ret: c_int| {
+        if ret < 0 {
+            io_error(std::io::Error::last_os_error());
+        }
+    };
+    if let Some(gid) = group {
+        check(unsafe {
+            fchownat(
+                file.as_raw_fd(),
+                b"\0".as_ptr() as *const c_char,
+                uid_t::MAX,
+                gid,
+                AT_EMPTY_PATH,
+            )
+        });
+    }
+    if let Some(mode) = attributes.mode {
+        check(unsafe { chmod(fd_path.as_ptr(), mode as mode_t) });
+    }
+    if let Some((secs, nanos)) = attributes.modified {
+        // Access times are left alone
+        let times = [
+            timespec {
+                tv_sec: 0,
+                tv_nsec: UTIME_OMIT,
+            },
+            timespec {
+                tv_sec: secs as time_t,
+                tv_nsec: nanos as c_long,
+            },
+        ];
+        check(unsafe { utimensat(AT_FDCWD, fd_path.as_ptr(), times.as_ptr(), 0) });
+    }
+}
+
+fn write_output(output: &[u8]) {
+    if stdout().write_all(output).is_err() {
+        file_not_found();
+    }
+}
+
+fn fail(code: ReturnCode) -> ! {
+    match code {
+        ReturnCode::FileNotFound => file_not_found(),
+        ReturnCode::PermissionDenied => permission_denied(),
+        ReturnCode::SymlinkDenied => symlink_denied(),
+        ReturnCode::LoginFailed => login_failed(),
+        ReturnCode::AccessDenied => access_denied(),
+        ReturnCode::InvalidInput => invalid_input(),
+        ReturnCode::PasswordExpired => password_expired(),
+        ReturnCode::Cancelled => cancelled(),
+        ReturnCode::TooLarge => too_large(),
+        ReturnCode::AlreadyExists => already_exists(),
+        _ => unknown_error(),
+    }
+}
+
+fn file_not_found() -> ! {
+    eprint!("File not found!");
+    std::process::exit(ReturnCode::FileNotFound as i32)
+}
+
+fn permission_denied() -> ! {
+    eprint!("Permission denied!");
+    std::process::exit(ReturnCode::PermissionDenied as i32)
+}
+
+fn unknown_error() -> ! {
+    eprint!("Unknown IO error!");
+    std::process::exit(ReturnCode::Unknown as i32)
+}
+
+fn login_failed() -> ! {
+    eprint!("Login failed!");
+    std::process::exit(ReturnCode::LoginFailed as i32)
+}
+
+fn access_denied() -> ! {
+    eprint!("Account not allowed to log in!");
+    std::process::exit(ReturnCode::AccessDenied as i32)
+}
+
+fn invalid_input() -> ! {
+    eprint!("Invalid input!");
+    std::process::exit(ReturnCode::InvalidInput as i32)
+}
+
+fn password_expired() -> ! {
+    eprint!("Password expired!");
+    std::process::exit(ReturnCode::PasswordExpired as i32)
+}
+
+fn too_large() -> ! {
+    eprint!("Too large!");
+    std::process::exit(ReturnCode::TooLarge as i32)
+}
+
+fn already_exists() -> ! {
+    eprint!("Already exists!");
+    std::process::exit(ReturnCode::AlreadyExists as i32)
+}
+
+fn symlink_denied() -> ! {
+    eprint!("Symbolic link not allowed!");
+    std::process::exit(ReturnCode::SymlinkDenied as i32)
+}
+
+fn special_file() -> ! {
+    eprint!("Not a regular file!");
+    std::process::exit(ReturnCode::SpecialFile as i32)
+}
+
+fn cancelled() -> ! {
+    eprint!("Cancelled!");
+    std::process::exit(ReturnCode::Cancelled as i32)
+}
+
+fn unexpected_type() -> ! {
+    eprint!("Unexpected type!");
+    std::process::exit(ReturnCode::UnexpectedType as i32)
+}
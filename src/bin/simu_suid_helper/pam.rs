@@ -0,0 +1,216 @@
+use std::cell::{Cell, RefCell};
+use std::ffi::CStr;
+use std::mem::size_of;
+use std::ptr;
+
+use libc::{c_char, c_int, c_void, calloc, free};
+use pam_sys::{
+    acct_mgmt, authenticate, chauthtok, end, start, PamConversation, PamFlag, PamHandle,
+    PamMessage, PamMessageStyle, PamResponse, PamReturnCode,
+};
+use simu::secret::Secret;
+use zeroize::Zeroize;
+
+/**
+ * A PAM transaction for one user, answering PAM's prompts with the given credentials.
+ * Without a password, password prompts fail the conversation.
+ * Messages from PAM are kept, to tell the user why a new password was refused.
+ *
+ * Replies are copied straight from the credentials into the buffers handed to PAM,
+ * which modules overwrite before freeing, so no other copies of the password are made.
+ */
+pub struct Pam<'a> {
+    handle: *mut PamHandle,
+    /// Pointed to by the handle, so it must stay in place for as long as the handle lives
+    conversation: Box<Conversation<'a>>,
+    status: PamReturnCode,
+}
+
+struct Conversation<'a> {
+    username: &'a CStr,
+    password: Option<&'a Secret>,
+    /// Set while changing the password
+    new_password: Cell<Option<&'a Secret>>,
+    messages: RefCell<Vec<String>>,
+}
+
+impl<'a> Pam<'a> {
+    pub fn start(
+        service: &str,
+        username: &'a CStr,
+        password: Option<&'a Secret>,
+    ) -> Result<Self, PamReturnCode> {
+        let conversation = Box::new(Conversation {
+            username,
+            password,
+            new_password: Cell::new(None),
+            messages: RefCell::new(Vec::new()),
+        });
+        let conv = PamConversation {
+            conv: Some(converse),
+            data_ptr: &*conversation as *const Conversation as *mut c_void,
+        };
+        let mut handle: *mut PamHandle = ptr::null_mut();
+        match start(service, username.to_str().ok(), &conv, &mut handle) {
+            PamReturnCode::SUCCESS => Ok(Self {
+                handle,
+                conversation,
+                status: PamReturnCode::SUCCESS,
+            }),
+            code => Err(code),
+        }
+    }
+
+    /**
+     * Checks the credentials, and whether the account may be used right now.
+     */
+    pub fn authenticate(&mut self) -> Result<(), PamReturnCode> {
+        let handle = unsafe { &mut *self.handle };
+        self.status = authenticate(handle, PamFlag::NONE);
+        if self.status == PamReturnCode::SUCCESS {
+            self.status = acct_mgmt(handle, PamFlag::NONE);
+        }
+        match self.status {
+            PamReturnCode::SUCCESS => Ok(()),
+            code => Err(code),
+        }
+    }
+
+    /**
+     * Checks only whether the account may be used right now, for users authenticated otherwise.
+     */
+    pub fn account(&mut self) -> Result<(), PamReturnCode> {
+        self.status = acct_mgmt(unsafe { &mut *self.handle }, PamFlag::NONE);
+        match self.status {
+            PamReturnCode::SUCCESS => Ok(()),
+            code => Err(code),
+        }
+    }
+
+    /**
+     * Changes the password, answering prompts for the current one with the password we were
+     * started with, and any others with the new one.
+     * If it has `expired`, only an expired password is changed, as PAM expects then.
+     */
+    pub fn change_password(
+        &mut self,
+        new_password: &'a Secret,
+        expired: bool,
+    ) -> Result<(), PamReturnCode> {
+        self.conversation.new_password.set(Some(new_password));
+        let flags = if expired {
+            PamFlag::CHANGE_EXPIRED_AUTHTOK
+        } else {
+            PamFlag::NONE
+        };
+        self.status = chauthtok(unsafe { &mut *self.handle }, flags);
+        self.conversation.new_password.set(None);
+        match self.status {
+            PamReturnCode::SUCCESS => Ok(()),
+            code => Err(code),
+        }
+    }
+
+    /**
+     * Errors and information PAM has shown so far.
+     */
+    pub fn messages(&self) -> Vec<String> {
+        self.conversation.messages.borrow().clone()
+    }
+}
+
+impl<'a> Drop for Pam<'a> {
+    fn drop(&mut self) {
+        end(unsafe { &mut *self.handle }, self.status);
+    }
+}
+
+impl<'a> Conversation<'a> {
+    /**
+     * Picks the password a prompt asks for.
+     *
+     * Modules ask for the current password while changing it as well, such as pam_unix's
+     * "Current password: ". We never set a locale, so their prompts are always in English.
+     */
+    fn password_for(&self, prompt: &str) -> Option<&'a Secret> {
+        let prompt = prompt.to_ascii_lowercase();
+        match self.new_password.get() {
+            Some(new_password) if !prompt.contains("current") && !prompt.contains("old") => {
+                Some(new_password)
+            }
+            _ => self.password,
+        }
+    }
+}
+
+extern "C" fn converse(
+    num_msg: c_int,
+    msg: *mut *mut PamMessage,
+    out_resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    let conversation = unsafe { &*(appdata_ptr as *const Conversation) };
+    let count = num_msg.max(0) as usize;
+    let resp = unsafe { calloc(count, size_of::<PamResponse>()) as *mut PamResponse };
+    if resp.is_null() {
+        return PamReturnCode::BUF_ERR as c_int;
+    }
+    for i in 0..count {
+        let (m, r) = unsafe { (&**msg.add(i), &mut *resp.add(i)) };
+        let text = if m.msg.is_null() {
+            Default::default()
+        } else {
+            unsafe { CStr::from_ptr(m.msg) }.to_string_lossy()
+        };
+        let reply = match PamMessageStyle::from(m.msg_style) {
+            PamMessageStyle::PROMPT_ECHO_ON => conversation.username.to_bytes(),
+            PamMessageStyle::PROMPT_ECHO_OFF => match conversation.password_for(&text) {
+                Some(password) => password.as_bytes(),
+                None => {
+                    unsafe { free_responses(resp, count) };
+                    return PamReturnCode::CONV_ERR as c_int;
+                }
+            },
+            PamMessageStyle::ERROR_MSG => {
+                eprintln!("[PAM ERROR] {}", text);
+                conversation.messages.borrow_mut().push(text.into_owned());
+                continue;
+            }
+            PamMessageStyle::TEXT_INFO => {
+                eprintln!("[PAM INFO] {}", text);
+                conversation.messages.borrow_mut().push(text.into_owned());
+                continue;
+            }
+        };
+        r.resp = copy_out(reply);
+        if r.resp.is_null() {
+            unsafe { free_responses(resp, count) };
+            return PamReturnCode::BUF_ERR as c_int;
+        }
+    }
+    unsafe { *out_resp = resp };
+    PamReturnCode::SUCCESS as c_int
+}
+
+/**
+ * Copies a reply into a NUL terminated buffer for PAM to free.
+ */
+fn copy_out(bytes: &[u8]) -> *mut c_char {
+    let buf = unsafe { calloc(bytes.len() + 1, 1) as *mut u8 };
+    if !buf.is_null() {
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len()) };
+    }
+    buf as *mut c_char
+}
+
+unsafe fn free_responses(resp: *mut PamResponse, count: usize) {
+    for i in 0..count {
+        let reply = (*resp.add(i)).resp;
+        if !reply.is_null() {
+            let len = libc::strlen(reply);
+            std::slice::from_raw_parts_mut(reply as *mut u8, len).zeroize();
+            free(reply as *mut c_void);
+        }
+    }
+    free(resp as *mut c_void);
+}
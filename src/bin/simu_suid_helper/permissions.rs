@@ -0,0 +1,79 @@
+use std::ffi::CString;
+use std::fs::Metadata;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use libc::{c_int, faccessat, geteuid, uid_t, AT_EACCESS, AT_FDCWD, R_OK, S_ISVTX, W_OK, X_OK};
+use simu::Permissions;
+
+/**
+ * What the user may do within a directory being listed, which decides whether its entries can be removed.
+ */
+pub struct DirPermissions {
+    /// Writable and enterable, as needed to remove anything from it
+    modifiable: bool,
+    /// Set for sticky directories such as `/tmp`, where entries can only be removed by their owner
+    /// or the directory's, this being the latter
+    sticky_owner: Option<uid_t>,
+    euid: uid_t,
+}
+
+impl DirPermissions {
+    pub fn new(dir: &Path) -> Self {
+        let sticky_owner = std::fs::metadata(dir)
+            .ok()
+            .filter(|meta| meta.mode() & S_ISVTX != 0)
+            .map(|meta| meta.uid());
+        Self {
+            modifiable: can_access(dir, W_OK | X_OK),
+            sticky_owner,
+            euid: unsafe { geteuid() },
+        }
+    }
+
+    /**
+     * Whether the user may create entries in the directory, as they may remove them.
+     */
+    pub fn modifiable(&self) -> bool {
+        self.modifiable
+    }
+
+    /**
+     * The user's permissions on an entry of the directory, `meta` being those of the entry itself.
+     * Links that are not followed are only checked for whether they can be removed.
+     */
+    pub fn entry(&self, path: &Path, meta: Option<&Metadata>, follow: bool) -> Permissions {
+        let delete = self.modifiable
+            && match self.sticky_owner {
+                Some(dir_owner) => {
+                    dir_owner == self.euid || meta.map(|m| m.uid()) == Some(self.euid)
+                }
+                None => true,
+            };
+        if !follow {
+            return Permissions {
+                delete,
+                ..Permissions::default()
+            };
+        }
+        Permissions {
+            read: can_access(path, R_OK),
+            write: can_access(path, W_OK),
+            execute: can_access(path, X_OK),
+            delete,
+        }
+    }
+}
+
+/**
+ * Asks the kernel whether we may access the path, with our effective ids like any other call would,
+ * following a symbolic link in its place.
+ */
+fn can_access(path: &Path, mode: c_int) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    unsafe { faccessat(AT_FDCWD, path.as_ptr(), mode, AT_EACCESS) == 0 }
+}
@@ -0,0 +1,148 @@
+use std::ffi::{CStr, CString};
+
+use libc::{
+    c_int, getegid, getgrnam, getgrouplist, getgroups, getpwnam, getpwuid, getuid, gid_t, uid_t,
+};
+use simu::config::{AccessPolicy, Server, Share};
+use simu::ReturnCode;
+
+/**
+ * Checks whether the account may log in at all, before anything is done on its behalf.
+ * Nonexistent accounts are reported as failed logins, as PAM would do.
+ */
+pub fn check_access(policy: &AccessPolicy, username: &CStr) -> Result<(), ReturnCode> {
+    let pwent = unsafe { getpwnam(username.as_ptr()) };
+    if pwent.is_null() {
+        return Err(ReturnCode::LoginFailed);
+    }
+    // Copied out right away, as the entry lives in static memory
+    let uid = unsafe { (*pwent).pw_uid };
+    let gid = unsafe { (*pwent).pw_gid };
+
+    // Any account with uid 0 is root, whatever it is called
+    if uid == 0 {
+        return Err(ReturnCode::AccessDenied);
+    }
+    if uid < policy.min_uid {
+        return Err(ReturnCode::AccessDenied);
+    }
+    if !policy.allowed_groups.is_empty() {
+        let groups = user_groups(username, gid);
+        let is_member = policy
+            .allowed_groups
+            .iter()
+            .filter_map(|name| group_id(name))
+            .any(|allowed| groups.contains(&allowed));
+        if !is_member {
+            return Err(ReturnCode::AccessDenied);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Checks the account anonymous visitors are served as.
+ * Accounts that could log in would do too, but root never will, whatever it is called.
+ */
+pub fn check_anonymous(username: &CStr) -> Result<(), ReturnCode> {
+    match uid_of(username) {
+        Some(0) | None => Err(ReturnCode::AccessDenied),
+        Some(_) => Ok(()),
+    }
+}
+
+/**
+ * Whether we were started by the server, which is trusted to have checked credentials
+ * we can't check ourselves. Anyone else could make those up.
+ */
+pub fn started_by_server(server: &Server) -> bool {
+    // The real uid, the effective one is root for anyone running us
+    let uid = unsafe { getuid() };
+    if uid == 0 {
+        return true;
+    }
+    let name = match server.user.as_deref().map(CString::new) {
+        Some(Ok(name)) => name,
+        _ => return false,
+    };
+    let pwent = unsafe { getpwnam(name.as_ptr()) };
+    !pwent.is_null() && unsafe { (*pwent).pw_uid } == uid
+}
+
+pub fn uid_of(username: &CStr) -> Option<uid_t> {
+    let pwent = unsafe { getpwnam(username.as_ptr()) };
+    if pwent.is_null() {
+        None
+    } else {
+        Some(unsafe { (*pwent).pw_uid })
+    }
+}
+
+/**
+ * Name of the account with this uid, for logins that only know the uid.
+ */
+pub fn username_of(uid: uid_t) -> Option<CString> {
+    let pwent = unsafe { getpwuid(uid) };
+    if pwent.is_null() {
+        None
+    } else {
+        // Copied out right away, as the entry lives in static memory
+        Some(unsafe { CStr::from_ptr((*pwent).pw_name) }.to_owned())
+    }
+}
+
+/**
+ * All groups of the user, including the primary group `gid`.
+ */
+pub fn user_groups(username: &CStr, gid: gid_t) -> Vec<gid_t> {
+    let mut count: c_int = 32;
+    loop {
+        let mut groups: Vec<gid_t> = vec![0; count as usize];
+        let ret = unsafe { getgrouplist(username.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if ret >= 0 {
+            groups.truncate(count as usize);
+            return groups;
+        }
+        // Buffer was too small, count now holds the needed size
+    }
+}
+
+pub fn group_id(name: &str) -> Option<gid_t> {
+    let name = CString::new(name).ok()?;
+    let grent = unsafe { getgrnam(name.as_ptr()) };
+    if grent.is_null() {
+        None
+    } else {
+        Some(unsafe { (*grent).gr_gid })
+    }
+}
+
+/**
+ * The group new files and directories are given, after switching to the user:
+ * the one asked for, or else the share's default if the user is a member. None leaves the kernel's choice.
+ * Asking for a group the user isn't in is refused, as the kernel would refuse handing files to it anyway.
+ */
+pub fn creation_group(share: &Share, requested: Option<&str>) -> Result<Option<gid_t>, ReturnCode> {
+    let own = |gid: &gid_t| unsafe { getegid() } == *gid || current_groups().contains(gid);
+    match requested {
+        Some(name) => match group_id(name) {
+            Some(gid) if own(&gid) => Ok(Some(gid)),
+            _ => Err(ReturnCode::AccessDenied),
+        },
+        None => Ok(share.group.as_deref().and_then(group_id).filter(own)),
+    }
+}
+
+/**
+ * Our supplementary groups, as set up when switching users.
+ */
+fn current_groups() -> Vec<gid_t> {
+    let count = unsafe { getgroups(0, std::ptr::null_mut()) };
+    if count <= 0 {
+        return Vec::new();
+    }
+    let mut groups: Vec<gid_t> = vec![0; count as usize];
+    let count = unsafe { getgroups(count, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    groups
+}
@@ -0,0 +1,67 @@
+use std::fs::Metadata;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use simu::config::{join_within, Share, SymlinkPolicy};
+use simu::ReturnCode;
+
+/**
+ * Resolves a path within a share, checking every symbolic link along the way
+ * against the share's symlink policy.
+ *
+ * This runs after switching users, so nothing found here is beyond what the user
+ * could reach by themselves. A link swapped in after being checked can still slip
+ * through, the final component is additionally opened with O_NOFOLLOW where needed.
+ */
+pub fn resolve(share: &Share, path: &Path) -> Result<PathBuf, ReturnCode> {
+    let full = join_within(&share.root, path).ok_or(ReturnCode::FileNotFound)?;
+    if share.symlinks == SymlinkPolicy::Follow {
+        return Ok(full);
+    }
+    let mut current = share.root.clone();
+    for component in path.components() {
+        current.push(component);
+        match std::fs::symlink_metadata(&current) {
+            Err(e) => return Err(io_error_code(e.kind())),
+            Ok(meta) => {
+                if meta.file_type().is_symlink() && !link_allowed(share, &current, &meta) {
+                    return Err(ReturnCode::SymlinkDenied);
+                }
+            }
+        }
+    }
+    Ok(full)
+}
+
+/**
+ * Decides whether the share's policy allows following the link at `link`,
+ * `link_meta` being the metadata of the link itself.
+ */
+pub fn link_allowed(share: &Share, link: &Path, link_meta: &Metadata) -> bool {
+    match share.symlinks {
+        SymlinkPolicy::Follow => true,
+        SymlinkPolicy::Never => false,
+        SymlinkPolicy::SameOwner => std::fs::metadata(link)
+            .map(|target| target.uid() == link_meta.uid())
+            .unwrap_or(false),
+        SymlinkPolicy::InsideRoot => {
+            match (
+                std::fs::canonicalize(&share.root),
+                std::fs::canonicalize(link),
+            ) {
+                (Ok(root), Ok(target)) => target.starts_with(root),
+                _ => false,
+            }
+        }
+    }
+}
+
+pub fn io_error_code(kind: ErrorKind) -> ReturnCode {
+    match kind {
+        ErrorKind::NotFound => ReturnCode::FileNotFound,
+        ErrorKind::PermissionDenied => ReturnCode::PermissionDenied,
+        ErrorKind::AlreadyExists => ReturnCode::AlreadyExists,
+        _ => ReturnCode::Unknown,
+    }
+}
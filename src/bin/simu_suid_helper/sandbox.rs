@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::Path;
+
+use landlock::{
+    path_beneath_rules, Access, AccessFs, AccessNet, BitFlags, PathBeneath, PathFd, Ruleset,
+    RulesetAttr, RulesetCreatedAttr, RulesetStatus, Scope, ABI,
+};
+use libc::EPERM;
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+use simu::config::Share;
+use simu::Operation;
+
+/**
+ * Confines the helper to what the operation needs, after switching to the user.
+ *
+ * Landlock limits filesystem access to the share root and the resolved target,
+ * which a symlink policy may have let lead outside of it. For uploads and new directories,
+ * that is the directory they are created in.
+ * Seccomp then refuses every syscall the operation has no use for, such as spawning processes
+ * or opening sockets, with EPERM.
+ *
+ * Either is skipped if the kernel lacks support, the returned line tells what was applied.
+ */
+pub fn restrict(share: &Share, target: &Path, operation: &Operation) -> String {
+    let landlock = match landlock(share, target, operation) {
+        Ok(RulesetStatus::FullyEnforced) => "full",
+        Ok(RulesetStatus::PartiallyEnforced) => "partial",
+        Ok(RulesetStatus::NotEnforced) | Err(_) => "off",
+    };
+    let seccomp = match seccomp(operation) {
+        Ok(()) => "on",
+        Err(_) => "off",
+    };
+    format!("landlock={} seccomp={}", landlock, seccomp)
+}
+
+/**
+ * Only restricts the calling thread, the cancellation watcher started before this
+ * does nothing but read stdin.
+ */
+fn landlock(
+    share: &Share,
+    target: &Path,
+    operation: &Operation,
+) -> Result<RulesetStatus, landlock::RulesetError> {
+    // Best effort, whatever the running kernel knows of is enforced
+    let abi = ABI::V6;
+    let access = match operation {
+        Operation::ReadFile | Operation::ReadDir => AccessFs::from_read(abi) & !AccessFs::Execute,
+        // Only creating, writing, and renaming into place or removing what turned out incomplete
+        Operation::Upload { .. } => AccessFs::MakeReg | AccessFs::WriteFile | AccessFs::RemoveFile,
+        Operation::MakeDir { .. } => AccessFs::MakeDir | AccessFs::RemoveDir,
+        // Landlock has no say over attributes, so nothing is allowed at all
+        Operation::SetAttributes(_) => BitFlags::EMPTY,
+        Operation::Tokens(_)
+        | Operation::ChangePassword(_)
+        | Operation::Links(_)
+        | Operation::Login => {
+            unreachable!("account operations don't touch shares")
+        }
+    };
+    let mut ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        // Handled without any rules, so no TCP connections at all
+        .handle_access(AccessNet::from_all(abi))?
+        .scope(Scope::from_all(abi))?
+        .create()?;
+    if !access.is_empty() {
+        ruleset = ruleset.add_rules(path_beneath_rules([&share.root], access))?;
+        // A target that doesn't exist will fail to open later on anyway
+        if let Ok(target) = PathFd::new(target) {
+            ruleset = ruleset.add_rule(PathBeneath::new(target, access))?;
+        }
+    }
+    Ok(ruleset.restrict_self()?.ruleset)
+}
+
+fn seccomp(operation: &Operation) -> Result<(), seccompiler::Error> {
+    let arch = std::env::consts::ARCH.try_into()?;
+    let rules = syscalls(operation)
+        .into_iter()
+        .map(|syscall| (syscall, Vec::new())) // No conditions on arguments
+        .collect::<BTreeMap<_, _>>();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(EPERM as u32),
+        SeccompAction::Allow,
+        arch,
+    )?;
+    let program: BpfProgram = filter.try_into()?;
+    seccompiler::apply_filter_all_threads(&program)
+}
+
+/**
+ * Syscalls made by the standard library and libc while performing the operation,
+ * including those for memory management, signals, timeouts and exiting.
+ */
+fn syscalls(operation: &Operation) -> Vec<i64> {
+    let mut syscalls = vec![
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_close,
+        libc::SYS_openat,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_lseek,
+        libc::SYS_fcntl,
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_sigaltstack,
+        libc::SYS_setitimer,
+        libc::SYS_clock_gettime,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_tgkill,
+        libc::SYS_getrandom,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    syscalls.extend_from_slice(&[
+        libc::SYS_alarm,
+        libc::SYS_open,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+    ]);
+    if matches!(operation, Operation::ReadFile | Operation::Upload { .. }) {
+        // Counting a use of the link once the file has been opened or stored
+        syscalls.extend_from_slice(&[libc::SYS_flock, libc::SYS_ftruncate, libc::SYS_fsync]);
+    }
+    match operation {
+        Operation::ReadFile => {}
+        Operation::ReadDir => {
+            syscalls.push(libc::SYS_getdents64);
+            // Symlink policies canonicalize the paths of entries
+            syscalls.extend_from_slice(&[libc::SYS_readlinkat, libc::SYS_getcwd]);
+            // Checking the user's permissions on entries, which libc may emulate with their ids
+            syscalls.extend_from_slice(&[
+                libc::SYS_faccessat,
+                libc::SYS_faccessat2,
+                libc::SYS_geteuid,
+                libc::SYS_getegid,
+                libc::SYS_getgroups,
+            ]);
+            #[cfg(target_arch = "x86_64")]
+            syscalls.push(libc::SYS_readlink);
+        }
+        Operation::Upload { .. } => {
+            syscalls.extend_from_slice(&[
+                libc::SYS_unlinkat,
+                libc::SYS_fchown,
+                libc::SYS_renameat,
+                libc::SYS_renameat2,
+            ]);
+            #[cfg(target_arch = "x86_64")]
+            syscalls.extend_from_slice(&[libc::SYS_unlink, libc::SYS_rename]);
+        }
+        Operation::MakeDir { .. } => {
+            syscalls.extend_from_slice(&[
+                libc::SYS_mkdirat,
+                libc::SYS_fchownat,
+                libc::SYS_unlinkat,
+            ]);
+            #[cfg(target_arch = "x86_64")]
+            syscalls.extend_from_slice(&[libc::SYS_mkdir, libc::SYS_rmdir]);
+        }
+        Operation::SetAttributes(_) => {
+            syscalls.extend_from_slice(&[
+                libc::SYS_fchownat,
+                libc::SYS_fchmodat,
+                libc::SYS_utimensat,
+            ]);
+            #[cfg(target_arch = "x86_64")]
+            syscalls.push(libc::SYS_chmod);
+        }
+        Operation::Tokens(_)
+        | Operation::ChangePassword(_)
+        | Operation::Links(_)
+        | Operation::Login => {
+            unreachable!("account operations don't touch shares")
+        }
+    }
+    syscalls
+}
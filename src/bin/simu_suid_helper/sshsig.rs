@@ -0,0 +1,318 @@
+use std::ffi::CStr;
+use std::fs::{self, OpenOptions};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use libc::{getpwnam, uid_t, O_NOFOLLOW, O_NONBLOCK};
+use percent_encoding::percent_decode_str;
+use ring::digest::{digest, SHA256, SHA512};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use simu::config::Config;
+use simu::{LinkOperation, Operation, SignedRequest, TokenOperation, SSH_SIGNATURE_NAMESPACE};
+
+/// Larger files are not read at all
+const MAX_AUTHORIZED_KEYS_SIZE: u64 = 1 << 20;
+
+/**
+ * Checks a signature in the format of `ssh-keygen -Y sign` over the request,
+ * made with one of the keys in the user's `~/.ssh/authorized_keys`.
+ *
+ * Done as root, so like sshd with `StrictModes`, the file and the directories leading to it
+ * must be owned by the user or root and not writable by others.
+ * Keys with options such as `command=` or `from=` are skipped, as they were meant to be restricted.
+ */
+pub fn verify(username: &CStr, request: &SignedRequest, signature: &[u8]) -> bool {
+    let sig = match SshSig::parse(signature) {
+        Some(sig) => sig,
+        None => return false,
+    };
+    if sig.namespace != SSH_SIGNATURE_NAMESPACE.as_bytes() {
+        return false;
+    }
+    let keys = authorized_keys(username);
+    if !keys.iter().any(|key| key[..] == *sig.public_key) {
+        return false;
+    }
+    let hash = match sig.hash_algorithm {
+        b"sha256" => digest(&SHA256, &request.message()),
+        b"sha512" => digest(&SHA512, &request.message()),
+        _ => return false,
+    };
+    // What was actually signed wraps the hash of the message
+    let mut signed = b"SSHSIG".to_vec();
+    for field in [
+        sig.namespace,
+        sig.reserved,
+        sig.hash_algorithm,
+        hash.as_ref(),
+    ] {
+        put_string(&mut signed, field);
+    }
+    verify_raw(sig.public_key, sig.signature, &signed)
+}
+
+/**
+ * Whether the signed request is the one we were asked to perform,
+ * so a signature can't be used for anything else.
+ */
+pub fn signs(
+    signed: &SignedRequest,
+    config: &Config,
+    share: &str,
+    path: &[u8],
+    operation: &Operation,
+) -> bool {
+    let signed_path = signed.path.split('?').next().unwrap_or_default();
+    let decoded: Vec<u8> = percent_decode_str(signed_path).collect();
+    let method = signed.method.as_str();
+    let signs_path = || {
+        decoded
+            .strip_prefix(b"/")
+            .and_then(|decoded| config.find_share(decoded))
+            .map(|(found, found_path)| found.name == share && found_path == path)
+            .unwrap_or(false)
+    };
+    match operation {
+        Operation::ReadFile | Operation::ReadDir => {
+            matches!(method, "GET" | "HEAD") && signs_path()
+        }
+        Operation::Upload { .. } => method == "PUT" && signs_path(),
+        Operation::MakeDir { .. } => method == "MKCOL" && signs_path(),
+        Operation::SetAttributes(_) => method == "PATCH" && signs_path(),
+        Operation::Tokens(operation) => {
+            let (expected_method, expected_path) = match operation {
+                TokenOperation::List => ("GET", "/.simu/tokens".to_string()),
+                TokenOperation::Mint(_) => ("POST", "/.simu/tokens".to_string()),
+                TokenOperation::Revoke(id) => ("DELETE", format!("/.simu/tokens/{}", id)),
+            };
+            method == expected_method && decoded == expected_path.as_bytes()
+        }
+        Operation::Links(operation) => {
+            let (expected_method, expected_path) = match operation {
+                LinkOperation::List => ("GET", "/.simu/links".to_string()),
+                LinkOperation::Create(_) => ("POST", "/.simu/links".to_string()),
+                LinkOperation::Revoke(id) => ("DELETE", format!("/.simu/links/{}", id)),
+            };
+            method == expected_method && decoded == expected_path.as_bytes()
+        }
+        Operation::Login => matches!(method, "GET" | "HEAD") && decoded == b"/",
+        // Needs the current password anyway
+        Operation::ChangePassword(_) => false,
+    }
+}
+
+struct SshSig<'a> {
+    public_key: &'a [u8],
+    namespace: &'a [u8],
+    reserved: &'a [u8],
+    hash_algorithm: &'a [u8],
+    signature: &'a [u8],
+}
+
+impl<'a> SshSig<'a> {
+    fn parse(blob: &'a [u8]) -> Option<Self> {
+        let mut reader = Reader(blob.strip_prefix(b"SSHSIG")?);
+        if reader.u32()? != 1 {
+            return None;
+        }
+        let sig = Self {
+            public_key: reader.string()?,
+            namespace: reader.string()?,
+            reserved: reader.string()?,
+            hash_algorithm: reader.string()?,
+            signature: reader.string()?,
+        };
+        reader.0.is_empty().then_some(sig)
+    }
+}
+
+/**
+ * Reads the SSH wire format, made of big endian integers and length prefixed strings.
+ */
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> Option<u32> {
+        if self.0.len() < 4 {
+            return None;
+        }
+        let (int, rest) = self.0.split_at(4);
+        self.0 = rest;
+        Some(u32::from_be_bytes(int.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        if len > self.0.len() {
+            return None;
+        }
+        let (string, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(string)
+    }
+
+    /**
+     * An unsigned integer, without the leading zero byte that keeps it positive.
+     */
+    fn mpint(&mut self) -> Option<&'a [u8]> {
+        let int = self.string()?;
+        Some(int.strip_prefix(&[0]).unwrap_or(int))
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, string: &[u8]) {
+    buf.extend_from_slice(&(string.len() as u32).to_be_bytes());
+    buf.extend_from_slice(string);
+}
+
+/**
+ * Verifies an SSH signature blob, as made by `public_key`, over `data`.
+ */
+fn verify_raw(public_key: &[u8], signature: &[u8], data: &[u8]) -> bool {
+    let (mut key, mut sig) = (Reader(public_key), Reader(signature));
+    let (key_type, sig_type, sig) = match (key.string(), sig.string(), sig.string()) {
+        (Some(key_type), Some(sig_type), Some(sig)) => (key_type, sig_type, sig),
+        _ => return false,
+    };
+    match (key_type, sig_type) {
+        (b"ssh-ed25519", b"ssh-ed25519") => match key.string() {
+            Some(point) => UnparsedPublicKey::new(&signature::ED25519, point)
+                .verify(data, sig)
+                .is_ok(),
+            None => false,
+        },
+        (b"ecdsa-sha2-nistp256", b"ecdsa-sha2-nistp256") => {
+            verify_ecdsa(&mut key, sig, 32, &signature::ECDSA_P256_SHA256_FIXED, data)
+        }
+        (b"ecdsa-sha2-nistp384", b"ecdsa-sha2-nistp384") => {
+            verify_ecdsa(&mut key, sig, 48, &signature::ECDSA_P384_SHA384_FIXED, data)
+        }
+        // Plain ssh-rsa signatures use SHA-1, so they are refused
+        (b"ssh-rsa", b"rsa-sha2-256") => {
+            verify_rsa(&mut key, sig, &signature::RSA_PKCS1_2048_8192_SHA256, data)
+        }
+        (b"ssh-rsa", b"rsa-sha2-512") => {
+            verify_rsa(&mut key, sig, &signature::RSA_PKCS1_2048_8192_SHA512, data)
+        }
+        _ => false,
+    }
+}
+
+fn verify_ecdsa(
+    key: &mut Reader,
+    sig: &[u8],
+    len: usize,
+    algorithm: &'static signature::EcdsaVerificationAlgorithm,
+    data: &[u8],
+) -> bool {
+    let point = match (key.string(), key.string()) {
+        (Some(_curve), Some(point)) => point,
+        _ => return false,
+    };
+    // SSH encodes the signature as two integers, ring takes them as fixed size big endian numbers
+    let mut sig = Reader(sig);
+    let mut fixed = vec![0u8; len * 2];
+    for (i, int) in [sig.mpint(), sig.mpint()].into_iter().enumerate() {
+        match int {
+            Some(int) if int.len() <= len => {
+                fixed[(i + 1) * len - int.len()..(i + 1) * len].copy_from_slice(int)
+            }
+            _ => return false,
+        }
+    }
+    UnparsedPublicKey::new(algorithm, point)
+        .verify(data, &fixed)
+        .is_ok()
+}
+
+fn verify_rsa(
+    key: &mut Reader,
+    sig: &[u8],
+    params: &'static signature::RsaParameters,
+    data: &[u8],
+) -> bool {
+    match (key.mpint(), key.mpint()) {
+        (Some(e), Some(n)) => RsaPublicKeyComponents { n, e }
+            .verify(params, data, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/**
+ * The key blobs in the user's `authorized_keys`, empty if it can't be trusted.
+ */
+fn authorized_keys(username: &CStr) -> Vec<Vec<u8>> {
+    let pwent = unsafe { getpwnam(username.as_ptr()) };
+    if pwent.is_null() {
+        return Vec::new();
+    }
+    let (uid, home) = unsafe {
+        (
+            (*pwent).pw_uid,
+            PathBuf::from(std::ffi::OsStr::from_bytes(
+                CStr::from_ptr((*pwent).pw_dir).to_bytes(),
+            )),
+        )
+    };
+    let ssh_dir = home.join(".ssh");
+    if !trusted(&home, uid) || !trusted(&ssh_dir, uid) {
+        return Vec::new();
+    }
+    // Not following links, and not blocking on a FIFO put in its place
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(O_NOFOLLOW | O_NONBLOCK)
+        .open(ssh_dir.join("authorized_keys"));
+    let file = match file {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    match file.metadata() {
+        Ok(meta)
+            if meta.is_file()
+                && owned_safely(&meta, uid)
+                && meta.len() <= MAX_AUTHORIZED_KEYS_SIZE => {}
+        _ => return Vec::new(),
+    }
+    let mut contents = String::new();
+    if file
+        .take(MAX_AUTHORIZED_KEYS_SIZE)
+        .read_to_string(&mut contents)
+        .is_err()
+    {
+        return Vec::new();
+    }
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let key_type = fields.next()?;
+            // Anything else in front of the key is options
+            if !matches!(
+                key_type,
+                "ssh-ed25519" | "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ssh-rsa"
+            ) {
+                return None;
+            }
+            STANDARD.decode(fields.next()?).ok()
+        })
+        .collect()
+}
+
+fn trusted(dir: &Path, uid: uid_t) -> bool {
+    match fs::metadata(dir) {
+        Ok(meta) => meta.is_dir() && owned_safely(&meta, uid),
+        Err(_) => false,
+    }
+}
+
+fn owned_safely(meta: &fs::Metadata, uid: uid_t) -> bool {
+    (meta.uid() == uid || meta.uid() == 0) && meta.mode() & 0o022 == 0
+}
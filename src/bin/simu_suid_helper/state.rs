@@ -0,0 +1,172 @@
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::{flock, LOCK_EX};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use simu::config::STATE_DIR;
+
+/// Random ids are this many bytes, hex encoded
+const ID_LEN: usize = 16;
+
+/**
+ * A directory of records in `STATE_DIR`, each in a file named by its id, only accessible to root.
+ */
+pub fn dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(STATE_DIR).join(name);
+    if let Err(e) = DirBuilder::new().recursive(true).mode(0o700).create(&dir) {
+        panic!("Cannot create {}: {}", dir.display(), e);
+    }
+    match fs::metadata(&dir) {
+        Ok(meta) if meta.uid() == 0 && meta.mode() & 0o022 == 0 => dir,
+        _ => panic!(
+            "{} must be owned by root and not writable by others",
+            dir.display()
+        ),
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/**
+ * A new id, too long to be guessed.
+ */
+pub fn new_id() -> String {
+    let mut id = [0u8; ID_LEN];
+    OsRng.fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+fn is_id(id: &str) -> bool {
+    id.len() == ID_LEN * 2 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+pub fn load<T: DeserializeOwned>(dir: &Path, id: &str) -> Option<T> {
+    // Checked first, as the id ends up in a path
+    if !is_id(id) {
+        return None;
+    }
+    let contents = fs::read_to_string(dir.join(format!("{}.toml", id))).ok()?;
+    toml::from_str(&contents).ok()
+}
+
+/**
+ * Replaces the record as a whole, so a crash can't leave half of it behind.
+ */
+pub fn store<T: Serialize>(dir: &Path, id: &str, record: &T) -> std::io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", id));
+    let contents = toml::to_string(record).expect("records are always serializable");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(format!("{}.toml", id)))
+}
+
+/**
+ * Locks the directory's records against other helpers until the returned file is dropped,
+ * for changes that depend on what a record held before.
+ */
+pub fn lock(dir: &Path) -> File {
+    let lock = lock_file(dir);
+    acquire(&lock);
+    lock
+}
+
+/**
+ * The file `lock` locks, for taking the lock with `acquire` later on,
+ * such as after switching users when the directory can't be opened anymore.
+ */
+pub fn lock_file(dir: &Path) -> File {
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(dir.join(".lock"));
+    match lock {
+        Ok(lock) => lock,
+        Err(e) => panic!("Cannot lock {}: {}", dir.display(), e),
+    }
+}
+
+pub fn acquire(lock: &File) {
+    if unsafe { flock(lock.as_raw_fd(), LOCK_EX) } != 0 {
+        panic!("Cannot lock: {}", std::io::Error::last_os_error());
+    }
+}
+
+/**
+ * Opens a record for `rewrite`, None if it does not exist.
+ */
+pub fn open(dir: &Path, id: &str) -> Option<File> {
+    if !is_id(id) {
+        return None;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dir.join(format!("{}.toml", id)))
+        .ok()
+}
+
+/**
+ * Reads a record opened with `open`, None if it has been removed since.
+ */
+pub fn read<T: DeserializeOwned>(file: &mut File) -> Option<T> {
+    if file.metadata().ok()?.nlink() == 0 {
+        return None;
+    }
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    toml::from_str(&contents).ok()
+}
+
+/**
+ * Replaces a record opened with `open` in place, for when it can't be replaced as a whole by `store`.
+ * Only to be done under the lock, which whoever reads the record must then take as well.
+ */
+pub fn rewrite<T: Serialize>(file: &mut File, record: &T) -> std::io::Result<()> {
+    let contents = toml::to_string(record).expect("records are always serializable");
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(contents.as_bytes())?;
+    file.set_len(contents.len() as u64)?;
+    file.sync_all()
+}
+
+pub fn remove(dir: &Path, id: &str) -> std::io::Result<()> {
+    fs::remove_file(dir.join(format!("{}.toml", id)))
+}
+
+/**
+ * All records in the directory.
+ */
+pub fn load_all<T: DeserializeOwned>(dir: &Path) -> Vec<T> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => panic!("Cannot list {}: {}", dir.display(), e),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            load(dir, name.strip_suffix(".toml")?)
+        })
+        .collect()
+}
@@ -0,0 +1,195 @@
+use std::path::PathBuf;
+
+use libc::uid_t;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use simu::secret::Secret;
+use simu::{MintedToken, NewToken, Operation, ReturnCode, TokenInfo, TokenOperation, TokenScope};
+
+use crate::state::{self, now};
+
+/// Makes tokens easy to recognize, such as for secret scanners
+const PREFIX: &str = "simu_";
+const SECRET_LEN: usize = 32;
+/// Keeps a single user from filling the disk with tokens
+const MAX_TOKENS_PER_USER: usize = 100;
+const MAX_NAME_LEN: usize = 100;
+
+/**
+ * An API token as kept on disk, in a file named by its id.
+ * Only a hash of the secret part is kept, the whole token is shown to its owner once when minted.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+    pub uid: uid_t,
+    /// SHA-256 of the secret part, hex encoded
+    hash: String,
+    pub info: TokenInfo,
+}
+
+fn dir() -> PathBuf {
+    state::dir("tokens")
+}
+
+fn hash(secret: &[u8]) -> String {
+    hex::encode(Sha256::digest(secret))
+}
+
+fn load(id: &str) -> Option<Record> {
+    state::load(&dir(), id)
+}
+
+fn store(record: &Record) -> std::io::Result<()> {
+    state::store(&dir(), &record.info.id, record)
+}
+
+fn records_of(uid: uid_t) -> Vec<Record> {
+    let mut records: Vec<Record> = state::load_all(&dir())
+        .into_iter()
+        .filter(|record: &Record| record.uid == uid)
+        .collect();
+    records.sort_by_key(|record| record.info.created);
+    records
+}
+
+/**
+ * Finds the record of a token, if the token is valid and has not expired.
+ */
+pub fn authenticate(token: &Secret) -> Option<Record> {
+    let token = std::str::from_utf8(token.as_bytes()).ok()?;
+    let (id, secret) = token.strip_prefix(PREFIX)?.split_once('_')?;
+    let record = load(id)?;
+    let expected = hex::decode(&record.hash).ok()?;
+    let actual = Sha256::digest(secret.as_bytes());
+    // Constant time, so the hash can't be guessed byte by byte
+    let difference = expected
+        .iter()
+        .zip(actual.iter())
+        .fold(expected.len() ^ actual.len(), |acc, (a, b)| {
+            acc | (a ^ b) as usize
+        });
+    if difference != 0 {
+        return None;
+    }
+    if record.info.expires.map(|t| t <= now()).unwrap_or(false) {
+        return None;
+    }
+    Some(record)
+}
+
+/**
+ * Notes that the token was just used, for its owner to see.
+ * Best effort, failing to store it shouldn't fail the request.
+ */
+pub fn touch(id: &str) {
+    // So a revocation in between can't have the record stored again
+    let _lock = state::lock(&dir());
+    if let Some(mut record) = load(id) {
+        record.info.last_used = Some(now());
+        let _ = store(&record);
+    }
+}
+
+/**
+ * Checks the request against what the token was limited to.
+ * Tokens can't be used to manage tokens or links, or change the password,
+ * so a leaked one can't be used to mint more, share files, or take over the account.
+ */
+pub fn check_scope(
+    scope: &TokenScope,
+    share: &str,
+    path: &[u8],
+    operation: &Operation,
+) -> Result<(), ReturnCode> {
+    if let Operation::Tokens(_) | Operation::ChangePassword(_) | Operation::Links(_) = operation {
+        return Err(ReturnCode::AccessDenied);
+    }
+    // Listing the shares shows them all, which tokens for one share shouldn't
+    if let Operation::Login = operation {
+        return match scope.share {
+            Some(_) => Err(ReturnCode::AccessDenied),
+            None => Ok(()),
+        };
+    }
+    if scope.read_only && !operation.is_read() {
+        return Err(ReturnCode::AccessDenied);
+    }
+    if scope.share.as_deref().map(|s| s != share).unwrap_or(false) {
+        return Err(ReturnCode::AccessDenied);
+    }
+    if let Some(prefix) = &scope.path_prefix {
+        let prefix = prefix.trim_matches('/').as_bytes();
+        let path = path.strip_suffix(b"/").unwrap_or(path);
+        let within = match path.strip_prefix(prefix) {
+            Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with(b"/"),
+            None => false,
+        };
+        if !within {
+            return Err(ReturnCode::AccessDenied);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Performs a token operation for the user, returning its output.
+ */
+pub fn run(operation: &TokenOperation, uid: uid_t) -> Result<Vec<u8>, ReturnCode> {
+    match operation {
+        TokenOperation::Mint(new) => {
+            mint(new, uid).map(|minted| bincode::serialize(&minted).unwrap())
+        }
+        TokenOperation::List => {
+            let infos: Vec<TokenInfo> = records_of(uid)
+                .into_iter()
+                .map(|record| record.info)
+                .collect();
+            Ok(bincode::serialize(&infos).unwrap())
+        }
+        TokenOperation::Revoke(id) => {
+            let _lock = state::lock(&dir());
+            match load(id) {
+                Some(record) if record.uid == uid => match state::remove(&dir(), id) {
+                    Ok(()) => Ok(Vec::new()),
+                    Err(e) => panic!("Cannot remove token: {}", e),
+                },
+                // Someone else's tokens are none of their business
+                _ => Err(ReturnCode::FileNotFound),
+            }
+        }
+    }
+}
+
+fn mint(new: &NewToken, uid: uid_t) -> Result<MintedToken, ReturnCode> {
+    if new.name.is_empty() || new.name.len() > MAX_NAME_LEN {
+        return Err(ReturnCode::InvalidInput);
+    }
+    if records_of(uid).len() >= MAX_TOKENS_PER_USER {
+        return Err(ReturnCode::PermissionDenied);
+    }
+    let mut secret = [0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    let (id, secret) = (state::new_id(), hex::encode(secret));
+    let info = TokenInfo {
+        id: id.clone(),
+        name: new.name.clone(),
+        created: now(),
+        expires: new.expires,
+        last_used: None,
+        scope: new.scope.clone(),
+    };
+    let record = Record {
+        uid,
+        hash: hash(secret.as_bytes()),
+        info: info.clone(),
+    };
+    if let Err(e) = store(&record) {
+        panic!("Cannot store token: {}", e);
+    }
+    Ok(MintedToken {
+        token: format!("{}{}_{}", PREFIX, id, secret),
+        info,
+    })
+}
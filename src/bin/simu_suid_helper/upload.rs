@@ -0,0 +1,257 @@
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{stdin, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use libc::{fchown, gid_t, signal, uid_t, EFBIG, EISDIR, SIGXFSZ, SIG_IGN};
+use simu::{read_body_chunk, ReturnCode, UploadedFile};
+
+use crate::resolve::io_error_code;
+use crate::{arm_timeout, remove_on_timeout};
+
+/// Longest name given to an uploaded file, in bytes
+const MAX_NAME_LEN: usize = 200;
+/// How many `name (n).ext` are tried before giving up
+const MAX_RENAMES: usize = 1000;
+/// Characters some systems can't have in names, or that mean something to a shell
+const REPLACED: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/**
+ * Turns the name an uploader gave their file into one that is safe to store it as.
+ *
+ * Only the last component of a path is kept, characters that could cause trouble are replaced,
+ * and leading dots are removed so nothing hidden or special is made. Names that end up empty are replaced.
+ */
+pub fn sanitize_name(name: &[u8]) -> Vec<u8> {
+    let name = name
+        .rsplit(|b| *b == b'/' || *b == b'\\')
+        .next()
+        .unwrap_or_default();
+    let name: String = String::from_utf8_lossy(name)
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if REPLACED.contains(&c) { '_' } else { c })
+        .collect();
+    // Spaces between dots would otherwise leave one in front
+    let name = name
+        .trim_start_matches(|c: char| c == '.' || c.is_whitespace())
+        .trim_end();
+    let mut name = if name.is_empty() { "upload" } else { name }.to_string();
+    if name.len() > MAX_NAME_LEN {
+        // Shortened from the end of the stem, keeping any reasonable extension
+        let (stem, extension) = match split_extension(&name) {
+            (stem, extension) if extension.len() <= 16 => (stem, extension),
+            _ => (name.as_str(), ""),
+        };
+        let mut end = MAX_NAME_LEN - extension.len();
+        while !stem.is_char_boundary(end) {
+            end -= 1;
+        }
+        name = format!("{}{}", &stem[..end], extension);
+    }
+    name.into_bytes()
+}
+
+/**
+ * The extension of a name without its dot, lowercased, if it has one.
+ */
+pub fn extension_of(name: &[u8]) -> Option<String> {
+    let name = String::from_utf8_lossy(name);
+    match split_extension(&name) {
+        (_, "") => None,
+        (_, extension) => Some(extension[1..].to_lowercase()),
+    }
+}
+
+/**
+ * Splits a name before the dot of its extension, if it has one.
+ */
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+/**
+ * Stores the body following the request as a file in `dir`, owned by `group` if given.
+ *
+ * With `replace`, the file is written under a temporary name and then renamed over any existing one,
+ * so readers see either the old or the whole new file. Otherwise an existing file is never replaced,
+ * the new one is given the first free name of `name (1).ext`, `name (2).ext` and so on.
+ *
+ * A file that turns out too large, or whose upload is cancelled or times out, is removed again.
+ */
+pub fn receive(
+    dir: &Path,
+    name: &[u8],
+    replace: bool,
+    group: Option<gid_t>,
+    max_size: Option<u64>,
+    idle_timeout: u64,
+) -> Result<UploadedFile, ReturnCode> {
+    // Exceeding the file size limit fails the write instead of killing us
+    unsafe {
+        signal(SIGXFSZ, SIG_IGN);
+    }
+    let (mut file, path, name) = if replace {
+        let (file, path, _) = create_new(dir, &temporary_name())?;
+        (file, path, name.to_vec())
+    } else {
+        create_new(dir, name)?
+    };
+    remove_on_timeout(Some(&path));
+    let mut size = 0u64;
+    let mut input = stdin().lock();
+    // Done by the kernel's rules, which only let owners hand files to groups they are in
+    let result = match group {
+        Some(gid) if unsafe { fchown(file.as_raw_fd(), uid_t::MAX, gid) } < 0 => {
+            Err(ReturnCode::PermissionDenied)
+        }
+        _ => Ok(()),
+    };
+    let result = result.and_then(|()| loop {
+        let chunk = match read_body_chunk(&mut input) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break file.sync_all().map_err(|e| io_error_code(e.kind())),
+            // The server closed our stdin before the body ended
+            Err(_) => break Err(ReturnCode::Cancelled),
+        };
+        size += chunk.len() as u64;
+        if max_size.map(|max| size > max).unwrap_or(false) {
+            break Err(ReturnCode::TooLarge);
+        }
+        if let Err(e) = file.write_all(&chunk) {
+            break Err(match e.raw_os_error() {
+                Some(EFBIG) => ReturnCode::TooLarge,
+                _ => io_error_code(e.kind()),
+            });
+        }
+        arm_timeout(idle_timeout);
+    });
+    let result = result.and_then(|()| {
+        if !replace {
+            return Ok(());
+        }
+        std::fs::rename(&path, dir.join(OsStr::from_bytes(&name))).map_err(|e| {
+            match e.raw_os_error() {
+                Some(EISDIR) => ReturnCode::AlreadyExists, // A directory is in the way
+                _ => io_error_code(e.kind()),
+            }
+        })
+    });
+    let result = match result {
+        Ok(()) => Ok(UploadedFile { name, size }),
+        Err(code) => {
+            drop(file);
+            let _ = std::fs::remove_file(&path);
+            Err(code)
+        }
+    };
+    remove_on_timeout(None);
+    result
+}
+
+/**
+ * A hidden name for a file being uploaded to replace another, unlikely to be taken.
+ */
+fn temporary_name() -> Vec<u8> {
+    format!(".simu-upload-{}", std::process::id()).into_bytes()
+}
+
+/**
+ * Creates the file under the first of its possible names that is free.
+ * Creating exclusively also refuses to follow a symbolic link in its place.
+ */
+fn create_new(dir: &Path, name: &[u8]) -> Result<(File, PathBuf, Vec<u8>), ReturnCode> {
+    let name_str = String::from_utf8_lossy(name);
+    let (stem, extension) = split_extension(&name_str);
+    for n in 0..MAX_RENAMES {
+        let candidate = if n == 0 {
+            name.to_vec()
+        } else {
+            format!("{} ({}){}", stem, n, extension).into_bytes()
+        };
+        let path = dir.join(OsStr::from_bytes(&candidate));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o666)
+            .open(&path);
+        match file {
+            Ok(file) => return Ok((file, path, candidate)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(io_error_code(e.kind())),
+        }
+    }
+    Err(ReturnCode::InvalidInput)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_last_component() {
+        assert_eq!(sanitize_name(b"../x"), b"x");
+        assert_eq!(sanitize_name(b"../../etc/passwd"), b"passwd");
+        assert_eq!(sanitize_name(b"a\\b"), b"b");
+        assert_eq!(sanitize_name(b"C:\\Users\\me\\report.pdf"), b"report.pdf");
+        assert_eq!(sanitize_name(b"dir/"), b"upload");
+    }
+
+    #[test]
+    fn removes_leading_dots() {
+        assert_eq!(sanitize_name(b".htaccess"), b"htaccess");
+        assert_eq!(sanitize_name(b"..."), b"upload");
+        assert_eq!(sanitize_name(b" . .bashrc"), b"bashrc");
+        assert_eq!(sanitize_name(b".. "), b"upload");
+        assert_eq!(sanitize_name(b"a.b.c"), b"a.b.c");
+    }
+
+    #[test]
+    fn replaces_troublesome_characters() {
+        assert_eq!(sanitize_name(b"a\nb\tc\x00d\x7f.txt"), b"abcd.txt");
+        assert_eq!(sanitize_name("a\u{85}b".as_bytes()), b"ab");
+        assert_eq!(sanitize_name(b"what?*<now>|\"x\":y"), b"what___now___x__y");
+        assert_eq!(
+            sanitize_name(b"\xff\xfe.txt"),
+            "\u{fffd}\u{fffd}.txt".as_bytes()
+        );
+        assert_eq!(sanitize_name(b""), b"upload");
+        assert_eq!(sanitize_name(b"\n\r"), b"upload");
+    }
+
+    #[test]
+    fn shortens_long_names() {
+        let name = sanitize_name(format!("{}.pdf", "a".repeat(300)).as_bytes());
+        assert_eq!(name.len(), MAX_NAME_LEN);
+        assert!(name.ends_with(b"a.pdf"));
+
+        // Cut at a character boundary, each of these being 3 bytes
+        let name = sanitize_name(format!("{}.txt", "€".repeat(100)).as_bytes());
+        let name = String::from_utf8(name).expect("still UTF-8");
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(name.len() > MAX_NAME_LEN - 3);
+        assert!(name.ends_with("€.txt"));
+
+        // Extensions too long to be one aren't kept
+        let name = sanitize_name(format!("a.{}", "é".repeat(150)).as_bytes());
+        let name = String::from_utf8(name).expect("still UTF-8");
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(name.starts_with("a."));
+    }
+
+    #[test]
+    fn finds_extensions() {
+        assert_eq!(extension_of(b"report.PDF").as_deref(), Some("pdf"));
+        assert_eq!(extension_of(b"archive.tar.gz").as_deref(), Some("gz"));
+        assert_eq!(extension_of(b"README"), None);
+        assert_eq!(extension_of(b"htaccess"), None);
+        assert_eq!(extension_of(b".htaccess"), None);
+        assert_eq!(extension_of(b"trailing."), Some(String::new()));
+    }
+}
@@ -0,0 +1,358 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Timeouts;
+
+/// Location of the configuration file, shared by the server and the helper.
+/// Fixed at build time, as the helper can't trust its environment.
+pub const CONFIG_PATH: &str = match option_env!("SIMU_CONFIG_PATH") {
+    Some(path) => path,
+    None => "/etc/simu/simu.toml",
+};
+
+/// Location of the mapping from client certificates to accounts, also fixed at build time.
+pub const CLIENT_CERTS_PATH: &str = match option_env!("SIMU_CLIENT_CERTS_PATH") {
+    Some(path) => path,
+    None => "/etc/simu/client-certs.toml",
+};
+
+/// Where the helper keeps its own state, such as API tokens, also fixed at build time.
+pub const STATE_DIR: &str = match option_env!("SIMU_STATE_DIR") {
+    Some(path) => path,
+    None => "/var/lib/simu",
+};
+
+/// Lowest uid allowed to log in unless configured otherwise, can be set at build time.
+pub const DEFAULT_MIN_UID: u32 = match option_env!("SIMU_MIN_UID") {
+    Some(uid) => parse_uid(uid),
+    None => 1000,
+};
+
+const fn parse_uid(uid: &str) -> u32 {
+    let bytes = uid.as_bytes();
+    let mut value = 0u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "SIMU_MIN_UID must be a number");
+        value = value * 10 + (bytes[i] - b'0') as u32;
+        i += 1;
+    }
+    value
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: Server,
+    #[serde(default)]
+    pub access: AccessPolicy,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default, rename = "share")]
+    pub shares: Vec<Share>,
+}
+
+/**
+ * The account the server runs as, switched to after binding if started as root.
+ *
+ * Credentials the helper can't check itself, such as client certificates,
+ * are only accepted from helpers started by this account or by root.
+ */
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Server {
+    pub user: Option<String>,
+}
+
+/**
+ * Which accounts may log in at all, checked by the helper before authentication.
+ * Accounts with uid 0 are always refused.
+ */
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AccessPolicy {
+    /// Accounts with a lower uid are refused, keeps out system and service accounts
+    #[serde(default = "default_min_uid")]
+    pub min_uid: u32,
+    /// If not empty, only members of these groups are allowed
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
+}
+
+fn default_min_uid() -> u32 {
+    DEFAULT_MIN_UID
+}
+
+impl Default for AccessPolicy {
+    fn default() -> Self {
+        Self {
+            min_uid: DEFAULT_MIN_UID,
+            allowed_groups: Vec::new(),
+        }
+    }
+}
+
+/**
+ * Resource limits the helper sets for itself after switching to the user,
+ * both as the soft and the hard limit. Limits that are not set are inherited from the server.
+ * Also how many helpers the server runs at once.
+ */
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Seconds of CPU time, the helper is killed once used up
+    pub cpu_time: Option<u64>,
+    /// Bytes of virtual memory
+    pub address_space: Option<u64>,
+    /// Open file descriptors
+    pub open_files: Option<u64>,
+    /// Bytes a written file may grow to
+    pub file_size: Option<u64>,
+    /// Processes the user may have running, counting those outside of simu
+    pub processes: Option<u64>,
+    /// Helpers the server runs at once, each being a process running PAM
+    #[serde(default = "default_max_helpers")]
+    pub max_helpers: usize,
+    /// Seconds a request waits for a free helper before being refused
+    #[serde(default = "default_helper_queue_timeout")]
+    pub helper_queue_timeout: u64,
+}
+
+fn default_max_helpers() -> usize {
+    64
+}
+
+fn default_helper_queue_timeout() -> u64 {
+    10
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            cpu_time: Some(600),
+            address_space: Some(1 << 30),
+            open_files: Some(64),
+            file_size: None,
+            processes: None,
+            max_helpers: default_max_helpers(),
+            helper_queue_timeout: default_helper_queue_timeout(),
+        }
+    }
+}
+
+/**
+ * A directory tree served under `/<name>/`.
+ *
+ * Without any configured shares, a single share with an empty name serves
+ * the working directory under `/`, as in earlier versions.
+ */
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Share {
+    pub name: String,
+    pub root: PathBuf,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Accept client certificates mapped in `CLIENT_CERTS_PATH` instead of passwords
+    #[serde(default)]
+    pub client_certificates: bool,
+    /// Serve requests without credentials as this unprivileged account, such as `nobody`
+    pub anonymous: Option<String>,
+    /// Group given to new files and directories when none is asked for, if the user is a member
+    pub group: Option<String>,
+    /// Permission bits taken away from new files and directories, such as `0o027`
+    pub umask: Option<u32>,
+}
+
+/**
+ * Which symbolic links are followed when resolving paths within a share.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Follow all links
+    #[default]
+    Follow,
+    /// Follow links whose target is within the share's root
+    InsideRoot,
+    /// Follow links owned by the owner of their target
+    SameOwner,
+    /// Never follow links
+    Never,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(&'static str, std::io::Error),
+    /// The file could be modified by someone other than root
+    Untrusted(&'static str),
+    Parse(&'static str, toml::de::Error),
+    DuplicateShare(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(fmt, "cannot read {}: {}", path, e),
+            Self::Untrusted(path) => write!(
+                fmt,
+                "{} must be owned by root and not writable by others",
+                path
+            ),
+            Self::Parse(path, e) => write!(fmt, "cannot parse {}: {}", path, e),
+            Self::DuplicateShare(name) => write!(fmt, "share '{}' is defined twice", name),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /**
+     * Loads the configuration from `CONFIG_PATH`, falling back to defaults if it does not exist.
+     */
+    pub fn load() -> Result<Self, ConfigError> {
+        let config: Self = match read_trusted(CONFIG_PATH)? {
+            Some(contents) => {
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(CONFIG_PATH, e))?
+            }
+            None => return Ok(Self::default().with_default_share()),
+        };
+        for (i, share) in config.shares.iter().enumerate() {
+            if config.shares[..i].iter().any(|s| s.name == share.name) {
+                return Err(ConfigError::DuplicateShare(share.name.clone()));
+            }
+        }
+        Ok(config.with_default_share())
+    }
+
+    fn with_default_share(mut self) -> Self {
+        if self.shares.is_empty() {
+            self.shares.push(Share {
+                name: String::new(),
+                root: PathBuf::from("."),
+                symlinks: SymlinkPolicy::default(),
+                client_certificates: false,
+                anonymous: None,
+                group: None,
+                umask: None,
+            });
+        }
+        self
+    }
+
+    pub fn share(&self, name: &str) -> Option<&Share> {
+        self.shares.iter().find(|s| s.name == name)
+    }
+
+    /**
+     * Splits a decoded URL path, without the leading slash, to the share it belongs to
+     * and the path within that share.
+     * Returns None for the top level listing of shares, or for unknown shares.
+     */
+    pub fn find_share<'a>(&self, path: &'a [u8]) -> Option<(&Share, &'a [u8])> {
+        if let Some(share) = self.share("") {
+            return Some((share, path));
+        }
+        let split = path.iter().position(|b| *b == b'/')?;
+        let name = std::str::from_utf8(&path[..split]).ok()?;
+        self.share(name).map(|share| (share, &path[split + 1..]))
+    }
+}
+
+/**
+ * Reads a file only root can have written, None if it does not exist.
+ */
+fn read_trusted(path: &'static str) -> Result<Option<String>, ConfigError> {
+    let meta = match std::fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ConfigError::Io(path, e)),
+    };
+    // The helper runs as root while reading this
+    if meta.uid() != 0 || meta.mode() & 0o022 != 0 {
+        return Err(ConfigError::Untrusted(path));
+    }
+    std::fs::read_to_string(path)
+        .map(Some)
+        .map_err(|e| ConfigError::Io(path, e))
+}
+
+/**
+ * Which accounts client certificates log in as, from `CLIENT_CERTS_PATH`.
+ */
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCertificates {
+    #[serde(default, rename = "certificate")]
+    pub mappings: Vec<CertificateMapping>,
+}
+
+/**
+ * Maps certificates matching all of the given names to `user`.
+ */
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateMapping {
+    /// Common name of the certificate's subject
+    pub common_name: Option<String>,
+    /// DNS name, email address or URI among the certificate's subject alternative names
+    pub san: Option<String>,
+    pub user: String,
+}
+
+impl ClientCertificates {
+    /**
+     * Loads the mapping from `CLIENT_CERTS_PATH`, mapping nothing if it does not exist.
+     */
+    pub fn load() -> Result<Self, ConfigError> {
+        match read_trusted(CLIENT_CERTS_PATH)? {
+            Some(contents) => {
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(CLIENT_CERTS_PATH, e))
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    /**
+     * The account of the first mapping matching a certificate with these names.
+     * Mappings without any names never match.
+     */
+    pub fn user_for(&self, common_names: &[&str], sans: &[&str]) -> Option<&str> {
+        self.mappings
+            .iter()
+            .find(|mapping| {
+                let cn_matches = mapping
+                    .common_name
+                    .as_deref()
+                    .map(|name| common_names.contains(&name));
+                let san_matches = mapping.san.as_deref().map(|name| sans.contains(&name));
+                match (cn_matches, san_matches) {
+                    (None, None) => false,
+                    (cn, san) => cn.unwrap_or(true) && san.unwrap_or(true),
+                }
+            })
+            .map(|mapping| mapping.user.as_str())
+    }
+}
+
+/**
+ * Joins a path within a share to the share's root,
+ * refusing anything that could climb out of it.
+ */
+pub fn join_within(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut full = root.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => full.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(full)
+}
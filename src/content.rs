@@ -0,0 +1,163 @@
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue, HOST,
+};
+use actix_web::{HttpRequest, HttpResponseBuilder};
+use lazy_static::lazy_static;
+use mime::Mime;
+
+/**
+ * Content served from users' files is sandboxed, as anything able to run script under
+ * our origin could reuse the visitor's credentials.
+ */
+const CONTENT_SECURITY_POLICY: &str =
+    "sandbox; default-src 'none'; img-src 'self' data:; media-src 'self'; style-src 'unsafe-inline'";
+
+lazy_static! {
+    /// Separate origin, such as `https://content.example.com`, where active content can be viewed inline
+    static ref CONTENT_ORIGIN: Option<String> = std::env::var("SIMU_CONTENT_ORIGIN")
+        .ok()
+        .map(|origin| origin.trim_end_matches('/').to_owned());
+}
+
+/// Signatures of common file formats, for files without a telling extension
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"OggS", "audio/ogg"),
+];
+
+/// Prefixes that make browsers consider a document to be markup
+const MARKUP: &[&[u8]] = &[
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<body",
+    b"<script",
+    b"<svg",
+    b"<?xml",
+];
+
+/**
+ * Decides the type of a file from its name, falling back to its first bytes.
+ */
+pub fn detect(name: &[u8], first_bytes: &[u8]) -> Mime {
+    let by_extension = std::str::from_utf8(name)
+        .ok()
+        .and_then(|name| mime_guess::from_path(name).first());
+    if let Some(mime) = by_extension {
+        return mime;
+    }
+    for (magic, mime) in MAGIC {
+        if first_bytes.starts_with(magic) {
+            return mime.parse().unwrap();
+        }
+    }
+    let start = first_bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .map(|i| &first_bytes[i..])
+        .unwrap_or_default();
+    if MARKUP.iter().any(|markup| {
+        start.len() >= markup.len() && start[..markup.len()].eq_ignore_ascii_case(markup)
+    }) {
+        return mime::TEXT_HTML;
+    }
+    mime::APPLICATION_OCTET_STREAM
+}
+
+/**
+ * Types which a browser may run script in when displayed.
+ */
+pub fn is_active(mime: &Mime) -> bool {
+    matches!(
+        (mime.type_().as_str(), mime.subtype().as_str()),
+        ("text", "html")
+            | ("text", "xml")
+            | ("text", "javascript")
+            | ("text", "ecmascript")
+            | ("application", "xhtml+xml")
+            | ("application", "xml")
+            | ("application", "javascript")
+            | ("application", "ecmascript")
+            | ("application", "x-shockwave-flash")
+            | ("image", "svg+xml")
+    ) || mime.suffix().map(|suffix| suffix == "xml").unwrap_or(false)
+}
+
+/**
+ * Whether the request was made to the separate content origin.
+ * Taken from the request itself, as anyone can send `X-Forwarded-Host` to pretend otherwise.
+ */
+pub fn is_content_origin(req: &HttpRequest) -> bool {
+    match &*CONTENT_ORIGIN {
+        Some(origin) => {
+            let host = origin
+                .split_once("://")
+                .map(|(_, host)| host)
+                .unwrap_or(origin);
+            // HTTP/2 requests carry it in the URI instead
+            let requested = req
+                .headers()
+                .get(HOST)
+                .and_then(|value| value.to_str().ok())
+                .or_else(|| req.uri().authority().map(|authority| authority.as_str()));
+            requested
+                .map(|requested| requested.eq_ignore_ascii_case(host))
+                .unwrap_or(false)
+        }
+        None => false,
+    }
+}
+
+/**
+ * Where an active file at `path` can be viewed inline, if a content origin is configured.
+ */
+pub fn view_url(path: &str) -> Option<String> {
+    CONTENT_ORIGIN
+        .as_ref()
+        .map(|origin| format!("{}{}", origin, path))
+}
+
+/**
+ * Sets the headers keeping browsers from running anything in a served file.
+ * Active content is forced to be downloaded unless viewed through the content origin.
+ */
+pub fn apply_headers(
+    builder: &mut HttpResponseBuilder,
+    req: &HttpRequest,
+    name: &[u8],
+    mime: Mime,
+) {
+    let disposition = if is_active(&mime) && !is_content_origin(req) {
+        DispositionType::Attachment
+    } else {
+        DispositionType::Inline
+    };
+    let ascii_name: String = name
+        .iter()
+        .map(|b| match b {
+            b' '..=b'~' if *b != b'"' && *b != b'\\' => *b as char,
+            _ => '_',
+        })
+        .collect();
+    builder
+        .content_type(mime)
+        .insert_header(("X-Content-Type-Options", "nosniff"))
+        .insert_header(("Content-Security-Policy", CONTENT_SECURITY_POLICY))
+        .insert_header(ContentDisposition {
+            disposition,
+            parameters: vec![
+                DispositionParam::Filename(ascii_name),
+                DispositionParam::FilenameExt(ExtendedValue {
+                    charset: Charset::Ext("UTF-8".to_owned()),
+                    language_tag: None,
+                    value: name.to_vec(),
+                }),
+            ],
+        });
+}
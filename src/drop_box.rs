@@ -0,0 +1,140 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpRequest, HttpResponse};
+use handlebars::Handlebars;
+use serde::Serialize;
+use simu::{ReturnCode, UploadedFile};
+use tracing::error;
+
+use crate::error::SimuError;
+use crate::helper::{self, Body};
+use crate::links::{self, visit_error};
+use crate::multipart::FilePart;
+use crate::security::{self, ServerKey};
+use crate::throttle::Throttle;
+use crate::upload::Uploaded;
+
+#[derive(Serialize)]
+struct Page {
+    csrf_token: Option<String>,
+    /// Name the last file was stored as
+    uploaded: Option<String>,
+}
+
+/**
+ * The page to upload to a drop box with, which shows nothing of what is already there.
+ */
+pub async fn page(
+    req: HttpRequest,
+    link: web::Path<String>,
+    key: web::Data<ServerKey>,
+) -> HttpResponse {
+    if links::verify(&key, b"drop-box", &link).is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+    render(
+        &req,
+        Page {
+            csrf_token: security::csrf_token(&req),
+            uploaded: None,
+        },
+    )
+}
+
+/**
+ * Takes a file from the page's form.
+ */
+pub async fn upload_form(
+    req: HttpRequest,
+    link: web::Path<String>,
+    payload: web::Payload,
+    key: web::Data<ServerKey>,
+    throttle: web::Data<Throttle>,
+) -> HttpResponse {
+    let id = match links::verify(&key, b"drop-box", &link) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let (name, part) = match FilePart::open(&req, payload.into_inner()).await {
+        Ok(file) => file,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    let body = futures::stream::unfold(part, |mut part| async move {
+        let chunk = part.next_chunk().await?;
+        Some((
+            chunk.map_err(|_| SimuError::new(ReturnCode::InvalidInput)),
+            part,
+        ))
+    });
+    match receive(&req, &throttle, id, &name, Box::pin(body)).await {
+        Ok(file) => render(
+            &req,
+            Page {
+                csrf_token: security::csrf_token(&req),
+                uploaded: Some(String::from_utf8_lossy(&file.name).into_owned()),
+            },
+        ),
+        Err(resp) => resp,
+    }
+}
+
+/**
+ * Takes a file as the body of a PUT to `/.simu/d/<link>/<name>`, for uploading without a browser.
+ */
+pub async fn upload(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    payload: web::Payload,
+    key: web::Data<ServerKey>,
+    throttle: web::Data<Throttle>,
+) -> HttpResponse {
+    let (link, name) = path.into_inner();
+    let id = match links::verify(&key, b"drop-box", &link) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let body = futures::StreamExt::map(payload, |chunk| {
+        chunk.map_err(|_| SimuError::new(ReturnCode::InvalidInput))
+    });
+    match receive(&req, &throttle, id, name.as_bytes(), Box::pin(body)).await {
+        Ok(file) => HttpResponse::Created().json(Uploaded::from(file)),
+        Err(resp) => resp,
+    }
+}
+
+/**
+ * Has the helper store the body in the drop box, as the drop box's creator.
+ */
+async fn receive(
+    req: &HttpRequest,
+    throttle: &Throttle,
+    id: &str,
+    name: &[u8],
+    body: Body,
+) -> Result<UploadedFile, HttpResponse> {
+    if name.contains(&0) {
+        return Err(HttpResponse::BadRequest().finish());
+    }
+    let (credentials, attempt) = links::credentials(req, throttle, id).map_err(|resp| *resp)?;
+    let result = helper::run_upload(credentials, "", name, None, body).await;
+    attempt.record(throttle, &result);
+    result.map_err(|err| visit_error(&err, req))
+}
+
+fn render(req: &HttpRequest, page: Page) -> HttpResponse {
+    let hb = match req.app_data::<web::Data<Handlebars>>() {
+        Some(hb) => hb,
+        None => {
+            error!("No Handlebars instance found! This is a bug!");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match hb.render("dropbox", &page) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(body),
+        Err(err) => {
+            error!("Failed to apply drop box template! {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
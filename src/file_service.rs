@@ -1,72 +1,276 @@
+use std::borrow::Cow;
+
 use actix_web::http::header::ContentType;
+use actix_web::http::Method;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
-use actix_web_httpauth::extractors::basic::BasicAuth;
+use futures::StreamExt;
 use handlebars::Handlebars;
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS};
 use serde::Serialize;
-use simu::{DirectoryEntry, ReturnCode};
-use tokio_stream::wrappers::ReceiverStream;
+use simu::config::Config;
+use simu::{Credentials, DirectoryEntry, EntryKind, Permissions, ReturnCode};
 use tracing::{error, info};
 
+use crate::content;
 use crate::error::SimuError;
+use crate::throttle::Throttle;
+use crate::{attributes, helper, login, security, upload};
 
-pub async fn serve_files(auth: BasicAuth, req: HttpRequest) -> impl Responder {
+pub async fn serve_files(
+    req: HttpRequest,
+    payload: web::Payload,
+    config: web::Data<Config>,
+    throttle: web::Data<Throttle>,
+) -> impl Responder {
     info!("request to default; {}", req.path());
-    if auth.password().is_none() {
-        return HttpResponse::Unauthorized().finish();
-    }
-    let filepath = req
-        .path()
-        .strip_prefix('/')
+    // File names are arbitrary bytes, so the path is decoded to exactly those
+    let decoded: Vec<u8> = percent_decode_str(req.path()).collect();
+    let filepath = decoded
+        .strip_prefix(b"/")
         .expect("path must start with forward-slash");
-    info!("considering path {}", filepath);
+    info!("considering path {}", String::from_utf8_lossy(filepath));
+    if filepath.contains(&0) {
+        return HttpResponse::NotFound().finish(); // Can't be passed to the kernel
+    }
+    let share = match config.find_share(filepath) {
+        Some((share, _)) => Some(share),
+        // Only to be redirected to the share's root
+        None => std::str::from_utf8(filepath)
+            .ok()
+            .and_then(|name| config.share(name)),
+    };
+    let anonymous = share
+        .map(|share| share.anonymous.is_some())
+        .unwrap_or(false);
+    let login = if anonymous {
+        login::credentials_or_anonymous(&req, &throttle)
+    } else {
+        login::credentials(&req, &throttle)
+    };
+    let (credentials, attempt) = match login {
+        Ok(login) => login,
+        Err(resp) => return *resp,
+    };
+
+    let (share, path) = match config.find_share(filepath) {
+        Some(found) => found,
+        None if filepath.is_empty() => {
+            // Not even the names of the shares are shown to just anyone
+            let resp = helper::run_login(credentials).await.map(|()| {
+                let shares: Vec<_> = config
+                    .shares
+                    .iter()
+                    .map(|share| DirectoryEntry::share(&share.name))
+                    .collect();
+                render_dir(&req, b"/", &shares, false)
+                    .unwrap_or_else(|_| HttpResponse::InternalServerError().finish())
+            });
+            attempt.record(&throttle, &resp);
+            return match resp {
+                Err(err) => error_response(&err, &req),
+                Ok(res) => res,
+            };
+        }
+        None if std::str::from_utf8(filepath)
+            .map(|name| config.share(name).is_some())
+            .unwrap_or(false) =>
+        {
+            return HttpResponse::Found()
+                .append_header(("Location", format!("{}/", req.path())))
+                .finish();
+        }
+        None => return HttpResponse::NotFound().finish(),
+    };
 
-    let resp = if filepath.ends_with('/') {
-        // todo proper path sep ref
-        serve_dir(auth, &req, filepath).await
-    } else if filepath.is_empty() {
-        serve_dir(auth, &req, "./").await // temporary workaround
+    // todo proper path sep ref
+    let is_dir = path.is_empty() || path.ends_with(b"/");
+    let method = req.method();
+    let read = matches!(*method, Method::GET | Method::HEAD);
+    // Files viewed on the content origin run their own scripts, which mustn't change anything
+    if !read && content::is_content_origin(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let resp = if read {
+        if is_dir {
+            // Anonymous visitors can't change anything, so they aren't offered to
+            let forms = !matches!(credentials, Credentials::Anonymous);
+            serve_dir(credentials, &req, &share.name, path, &decoded, forms).await
+        } else {
+            serve_file(credentials, &req, &share.name, path).await
+        }
+    } else if *method == Method::PUT && !is_dir {
+        let payload = payload.into_inner();
+        upload::put(credentials, &req, &share.name, path, payload).await
+    } else if *method == Method::POST && is_dir {
+        let payload = payload.into_inner();
+        upload::post_form(credentials, &req, &share.name, path, payload).await
+    } else if *method == Method::PATCH {
+        let payload = payload.into_inner();
+        attributes::patch(credentials, &req, &share.name, path, payload).await
+    } else if method.as_str() == "MKCOL" {
+        upload::mkcol(credentials, &req, &share.name, path).await
     } else {
-        serve_file(auth, filepath).await
+        return HttpResponse::MethodNotAllowed().finish();
     };
+    attempt.record(&throttle, &resp);
 
     match resp {
-        Err(err) => match err.code {
-            ReturnCode::FileNotFound => HttpResponse::NotFound().finish(),
-            ReturnCode::LoginFailed => HttpResponse::Unauthorized().finish(),
-            ReturnCode::PermissionDenied => HttpResponse::Forbidden().finish(),
-            ReturnCode::UnexpectedType => HttpResponse::Found()
-                .append_header(("Location", format!("/{}/",filepath)))
-                .finish(),
-            _ => HttpResponse::InternalServerError().finish(),
-        },
+        Err(err) => error_response(&err, &req),
         Ok(res) => res,
     }
 }
 
-async fn serve_file(auth: BasicAuth, filepath: &str) -> Result<HttpResponse, SimuError> {
-    let res = crate::helper::run_file(
-        auth.user_id(),
-        auth.password().expect("Password missing"),
-        filepath,
-    )
-    .await?;
+/**
+ * Answers a request the helper failed.
+ */
+pub fn error_response(err: &SimuError, req: &HttpRequest) -> HttpResponse {
+    match err.code {
+        ReturnCode::FileNotFound => HttpResponse::NotFound().finish(),
+        ReturnCode::LoginFailed => HttpResponse::Unauthorized().finish(),
+        ReturnCode::PermissionDenied
+        | ReturnCode::SpecialFile
+        | ReturnCode::SymlinkDenied
+        | ReturnCode::AccessDenied => HttpResponse::Forbidden().finish(),
+        ReturnCode::Timeout => HttpResponse::GatewayTimeout().finish(),
+        ReturnCode::Busy => HttpResponse::ServiceUnavailable()
+            .append_header(("Retry-After", "5"))
+            .finish(),
+        ReturnCode::InvalidInput => HttpResponse::BadRequest().finish(),
+        ReturnCode::TooLarge => HttpResponse::PayloadTooLarge().finish(),
+        ReturnCode::AlreadyExists => HttpResponse::Conflict().finish(),
+        ReturnCode::PasswordExpired => HttpResponse::SeeOther()
+            .append_header(("Location", "/.simu/password?expired=true"))
+            .finish(),
+        ReturnCode::UnexpectedType => HttpResponse::Found()
+            .append_header(("Location", format!("{}/", req.path())))
+            .finish(),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn serve_file(
+    credentials: Credentials,
+    req: &HttpRequest,
+    share: &str,
+    path: &[u8],
+) -> Result<HttpResponse, SimuError> {
+    let mut stream = crate::helper::run_file(credentials, share, path).await?;
+
+    // Peek at the start of the file to tell its type if the name doesn't
+    let first = stream.recv().await.transpose()?.unwrap_or_default();
+    let name = path.rsplit(|b| *b == b'/').next().unwrap_or(path);
+    let mime = content::detect(name, &first);
 
-    let stream = ReceiverStream::new(res);
-    Ok(HttpResponse::Ok().streaming::<_, crate::error::SimuError>(stream))
+    let mut resp = HttpResponse::Ok();
+    content::apply_headers(&mut resp, req, name, mime);
+    let stream = futures::stream::once(async { Ok(first) }).chain(stream);
+    Ok(resp.streaming::<_, crate::error::SimuError>(stream))
 }
 
-async fn serve_dir(
-    auth: BasicAuth,
+/**
+ * Lists a directory, with `forms` to upload files and create directories in it
+ * if the user may do so.
+ */
+pub async fn serve_dir(
+    credentials: Credentials,
     req: &HttpRequest,
-    dirpath: &str,
+    share: &str,
+    dirpath: &[u8],
+    urlpath: &[u8],
+    forms: bool,
 ) -> Result<HttpResponse, SimuError> {
-    let dir = crate::helper::run_dir(
-        auth.user_id(),
-        auth.password().expect("Password missing"),
-        dirpath,
-    )
-    .await?;
+    let dir = crate::helper::run_dir(credentials, share, dirpath).await?;
+
+    render_dir(req, urlpath, &dir.entries, forms && dir.modifiable)
+}
+
+/**
+ * Characters that can't appear as is in a relative link to an entry.
+ * Includes ':' so a name can't be mistaken for an URL scheme.
+ */
+pub const NAME_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'/')
+    .add(b':')
+    .add(b';')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// What the directory template gets to see of an entry
+#[derive(Serialize)]
+struct EntryView<'a> {
+    name: String,
+    href: String,
+    view_href: Option<String>,
+    is_dir: bool,
+    kind: EntryKind,
+    link_target: Option<Cow<'a, str>>,
+    downloadable: bool,
+    /// Downloadable, and the user may read it, or list and enter it for directories
+    openable: bool,
+    permissions: Permissions,
+}
+
+impl<'a> EntryView<'a> {
+    /**
+     * `dir_url` is the still encoded URL of the directory being listed.
+     */
+    fn new(entry: &'a DirectoryEntry, dir_url: &str) -> Self {
+        let mut name = String::from_utf8_lossy(&entry.name).into_owned();
+        let mut href = percent_encode(&entry.name, NAME_ENCODE_SET).to_string();
+        if entry.is_dir {
+            name.push('/');
+            href.push('/');
+        }
+        // Active content is only downloaded here, but can be viewed on the content origin
+        let permissions = entry.permissions;
+        let openable =
+            entry.downloadable && permissions.read && (!entry.is_dir || permissions.execute);
+        let view_href = if !entry.is_dir
+            && openable
+            && content::is_active(&content::detect(&entry.name, &[]))
+        {
+            content::view_url(&format!("{}{}", dir_url, href))
+        } else {
+            None
+        };
+        Self {
+            name,
+            href,
+            view_href,
+            is_dir: entry.is_dir,
+            kind: entry.kind,
+            link_target: entry
+                .link_target
+                .as_ref()
+                .map(|target| String::from_utf8_lossy(target)),
+            downloadable: entry.downloadable,
+            openable,
+            permissions,
+        }
+    }
+}
 
+fn render_dir(
+    req: &HttpRequest,
+    dirpath: &[u8],
+    entries: &[DirectoryEntry],
+    forms: bool,
+) -> Result<HttpResponse, SimuError> {
     let hb = req.app_data::<web::Data<Handlebars>>().map(|h| h.get_ref());
     if hb.is_none() {
         error!("No Handlebars instance found! This is a bug!");
@@ -75,15 +279,22 @@ async fn serve_dir(
 
     #[derive(Serialize)]
     struct Dir<'a> {
-        path: &'a str,
-        entries: &'a [DirectoryEntry],
+        path: Cow<'a, str>,
+        entries: Vec<EntryView<'a>>,
+        csrf_token: Option<String>,
+        forms: bool,
     }
 
     let body = hb.unwrap().render(
         "directory",
         &Dir {
-            path: dirpath,
-            entries: &dir.0,
+            path: String::from_utf8_lossy(dirpath),
+            entries: entries
+                .iter()
+                .map(|entry| EntryView::new(entry, req.path()))
+                .collect(),
+            csrf_token: security::csrf_token(req),
+            forms,
         },
     );
     match body {
@@ -1,20 +1,46 @@
+use std::future::Future;
 use std::io::{ErrorKind, Read, Write};
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 use bytes::Bytes;
+use futures::{Stream, StreamExt};
 use lazy_static::lazy_static;
-use simu::{Directory, ReturnCode};
-use tokio::sync::mpsc;
+use serde::de::DeserializeOwned;
+use simu::config::Limits;
+use simu::secret::Secret;
+use simu::{
+    write_body_chunk, Attributes, Credentials, Directory, HelperRequest, LinkOperation, Operation,
+    PasswordChange, ReturnCode, Timeouts, TokenOperation, UploadedFile,
+};
+use tokio::sync::{mpsc, Semaphore};
 use tokio::task;
+use tokio::time::{sleep, timeout, Instant, Sleep};
 use tracing::{debug, error, warn};
 
 use crate::error::SimuError;
+use crate::metrics;
 
 const BUFFER_SIZE: usize = 65536;
+/// Extra time given to the helper to notice its own timeouts before we enforce ours
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+static SANDBOX_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// The body of an upload, as it arrives from the client
+pub type Body = Pin<Box<dyn Stream<Item = Result<Bytes, SimuError>>>>;
 
 lazy_static! {
     static ref SUID_LOC: Box<Path> = {
+        if let Some(path) = std::env::var_os("SIMU_HELPER") {
+            // For systems where the helper can't be made SUID in place, such as NixOS
+            return PathBuf::from(path).into_boxed_path();
+        }
         // Hacky, find executable's path, and assume suid helper is next to it
         // Makes using debug and release modes side-by-side more easy
         let mut path = std::env::current_exe().unwrap().parent().unwrap().to_path_buf();
@@ -22,70 +48,250 @@ lazy_static! {
         debug!("suid helper: {:?}", path);
         path.into_boxed_path()
     };
+    /// Each helper is a process running PAM, so only this many are allowed at once
+    static ref HELPER_SLOTS: Arc<Semaphore> =
+        Arc::new(Semaphore::new(MAX_HELPERS.load(Ordering::Relaxed)));
+}
+
+/// From the configuration, set by `configure` before any helper runs
+static MAX_HELPERS: AtomicUsize = AtomicUsize::new(0);
+/// How long a request waits for a free slot before being refused
+static QUEUE_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// From the configuration, also set by `configure`
+static AUTH_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+static FIRST_BYTE_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+static IDLE_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * Takes how many helpers may run at once and how long they may take from the configuration,
+ * to be called once at startup.
+ */
+pub fn configure(limits: &Limits, timeouts: Timeouts) {
+    MAX_HELPERS.store(limits.max_helpers, Ordering::Relaxed);
+    QUEUE_TIMEOUT.store(limits.helper_queue_timeout, Ordering::Relaxed);
+    AUTH_TIMEOUT.store(timeouts.auth, Ordering::Relaxed);
+    FIRST_BYTE_TIMEOUT.store(timeouts.first_byte, Ordering::Relaxed);
+    IDLE_TIMEOUT.store(timeouts.idle, Ordering::Relaxed);
+}
+
+fn timeouts() -> Timeouts {
+    Timeouts {
+        auth: AUTH_TIMEOUT.load(Ordering::Relaxed),
+        first_byte: FIRST_BYTE_TIMEOUT.load(Ordering::Relaxed),
+        idle: IDLE_TIMEOUT.load(Ordering::Relaxed),
+    }
+}
+
+fn as_duration(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs) + TIMEOUT_GRACE)
+    }
+}
+
+/**
+ * Output of a running helper.
+ *
+ * Holds the write end of the helper's stdin, dropping this before the helper
+ * is done closes it, which the helper takes as a request to stop.
+ */
+pub struct HelperOutput {
+    rx: mpsc::Receiver<Result<Bytes, SimuError>>,
+    _stdin: ChildStdin,
+    /// Fires when the helper has not produced anything for the idle timeout
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl HelperOutput {
+    pub async fn recv(&mut self) -> Option<Result<Bytes, SimuError>> {
+        futures::StreamExt::next(self).await
+    }
+}
+
+impl Stream for HelperOutput {
+    type Item = Result<Bytes, SimuError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(item) = self.rx.poll_recv(cx) {
+            if let (Some(idle), Some(duration)) = (&mut self.idle, as_duration(timeouts().idle)) {
+                idle.as_mut().reset(Instant::now() + duration);
+            }
+            return Poll::Ready(item);
+        }
+        let timed_out = match &mut self.idle {
+            Some(idle) => idle.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+        if !timed_out {
+            return Poll::Pending;
+        }
+        warn!("Helper output idle for too long, giving up");
+        self.idle = None;
+        self.rx.close();
+        Poll::Ready(Some(Err(SimuError::new(ReturnCode::Timeout))))
+    }
 }
 
 pub async fn run_file(
-    usern: &str,
-    passw: &str,
-    path: &str,
-) -> Result<mpsc::Receiver<Result<Bytes, SimuError>>, SimuError> {
-    let mut input = build_partial_input(usern, passw, path);
+    credentials: Credentials,
+    share: &str,
+    path: &[u8],
+) -> Result<HelperOutput, SimuError> {
+    run_helper(
+        build_request(credentials, share, path, Operation::ReadFile),
+        None,
+    )
+    .await
+}
 
-    input.extend(b"FIL");
-    input.push(0);
-    run_helper(input).await
+pub async fn run_dir(
+    credentials: Credentials,
+    share: &str,
+    path: &[u8],
+) -> Result<Directory, SimuError> {
+    let request = build_request(credentials, share, path, Operation::ReadDir);
+    read_output(run_helper(request, None).await?).await
 }
 
-pub async fn run_dir(usern: &str, passw: &str, path: &str) -> Result<Directory, SimuError> {
-    let mut input = build_partial_input(usern, passw, path);
+pub async fn run_tokens<T: DeserializeOwned>(
+    credentials: Credentials,
+    operation: TokenOperation,
+) -> Result<T, SimuError> {
+    let request = build_request(credentials, "", b"", Operation::Tokens(operation));
+    read_output(run_helper(request, None).await?).await
+}
 
-    input.extend(b"DIR");
-    input.push(0);
+pub async fn run_links<T: DeserializeOwned>(
+    credentials: Credentials,
+    operation: LinkOperation,
+) -> Result<T, SimuError> {
+    let request = build_request(credentials, "", b"", Operation::Links(operation));
+    read_output(run_helper(request, None).await?).await
+}
 
-    let mut recv = run_helper(input).await?;
+pub async fn run_password(
+    credentials: Credentials,
+    new_password: Secret,
+) -> Result<PasswordChange, SimuError> {
+    let request = build_request(
+        credentials,
+        "",
+        b"",
+        Operation::ChangePassword(new_password),
+    );
+    read_output(run_helper(request, None).await?).await
+}
+
+/**
+ * Stores the body as a file at the path, returning the name it was stored under.
+ */
+pub async fn run_upload(
+    credentials: Credentials,
+    share: &str,
+    path: &[u8],
+    group: Option<String>,
+    body: Body,
+) -> Result<UploadedFile, SimuError> {
+    let request = build_request(credentials, share, path, Operation::Upload { group });
+    read_output(run_helper(request, Some(body)).await?).await
+}
+
+pub async fn run_mkdir(
+    credentials: Credentials,
+    share: &str,
+    path: &[u8],
+    group: Option<String>,
+) -> Result<(), SimuError> {
+    let request = build_request(credentials, share, path, Operation::MakeDir { group });
+    read_output(run_helper(request, None).await?).await
+}
+
+pub async fn run_attributes(
+    credentials: Credentials,
+    share: &str,
+    path: &[u8],
+    attributes: Attributes,
+) -> Result<(), SimuError> {
+    let request = build_request(
+        credentials,
+        share,
+        path,
+        Operation::SetAttributes(attributes),
+    );
+    read_output(run_helper(request, None).await?).await
+}
+
+/**
+ * Checks the credentials without doing anything else, for what needs a login but no share.
+ */
+pub async fn run_login(credentials: Credentials) -> Result<(), SimuError> {
+    let request = build_request(credentials, "", b"", Operation::Login);
+    read_output(run_helper(request, None).await?).await
+}
+
+/**
+ * Collects the whole output of the helper, and decodes it.
+ */
+async fn read_output<T: DeserializeOwned>(mut recv: HelperOutput) -> Result<T, SimuError> {
     let mut buf = Vec::with_capacity(BUFFER_SIZE);
     while let Some(bytes) = recv.recv().await {
         let bytes = bytes?;
         buf.extend_from_slice(&bytes[..]);
     }
-    let dir = bincode::deserialize(&buf[..]);
-    if dir.is_err() {
+    bincode::deserialize(&buf[..]).map_err(|_| {
         error!("Error while deserializing helper output!");
-        Err(SimuError::unknown())
-    } else {
-        Ok(dir.unwrap())
-    }
+        SimuError::unknown()
+    })
 }
 
-async fn run_helper(input: Vec<u8>) -> Result<mpsc::Receiver<Result<Bytes, SimuError>>, SimuError> {
+async fn run_helper(request: HelperRequest, body: Option<Body>) -> Result<HelperOutput, SimuError> {
+    // Held until the helper exits
+    let permit = match timeout(
+        Duration::from_secs(QUEUE_TIMEOUT.load(Ordering::Relaxed)),
+        HELPER_SLOTS.clone().acquire_owned(),
+    )
+    .await
+    {
+        Ok(Ok(permit)) => permit,
+        _ => {
+            warn!("Too many helpers running, refusing request");
+            metrics::HELPERS_REJECTED.inc();
+            return Err(SimuError::new(ReturnCode::Busy));
+        }
+    };
+    let command = Command::new(&**SUID_LOC)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut command = match command {
+        Ok(command) => command,
+        Err(e) => {
+            error!("Failed to open helper command! {}", e);
+            return Err(SimuError::unknown());
+        }
+    };
+    metrics::HELPERS_STARTED.inc();
+
+    // The helper switches to an uid we are not allowed to signal, so instead of killing it
+    // we keep its stdin open for as long as someone is interested in the output.
+    // Dropping it, be it due to this future getting dropped or the HTTP client going away
+    // mid-transfer, tells the helper to stop.
+    let stdin = command.stdin.take().unwrap();
+    let mut stdout = command.stdout.take().unwrap();
+    let mut stderr = command.stderr.take().unwrap();
+
     // Data channel
     let (tx, rx) = mpsc::channel::<Result<Bytes, SimuError>>(16);
     // Error/success channel
     let (etx, mut erx) = mpsc::channel::<Result<(), ReturnCode>>(1);
     // Small buffer also forces bad callpath blocking issues to arise
     let _res = task::spawn_blocking(move || {
-        let command = Command::new(&**SUID_LOC)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn();
-        if let Err(ref e) = command {
-            if !tx.is_closed() {
-                error!("Failed to open helper command! {}", e);
-                etx.blocking_send(Err(ReturnCode::Unknown)).unwrap();
-            }
-        }
-        let mut command = command.unwrap();
-        let mut stdin = command.stdin.take().unwrap();
-        let mut stdout = command.stdout.take().unwrap();
-        let mut stderr = command.stderr.take().unwrap();
         let mut did_data_arrive = false;
+        let mut client_left = false;
 
-        let res = stdin.write(&input);
-        if let Err(e) = res {
-            error!("Failed to write info to helper! {}", e);
-            etx.blocking_send(Err(ReturnCode::Unknown)).unwrap();
-        }
         loop {
             let mut buf = [0u8; BUFFER_SIZE];
             let res = stdout.read(&mut buf);
@@ -95,36 +301,34 @@ async fn run_helper(input: Vec<u8>) -> Result<mpsc::Receiver<Result<Bytes, SimuE
                         continue;
                     }
                     error!("Failed to read stdout from the helper! {}", e);
-                    tx.blocking_send(Ok(Bytes::new())).unwrap();
+                    let _ = tx.blocking_send(Ok(Bytes::new()));
+                    break;
                 }
                 Ok(sz) => {
                     if sz == 0 {
-                        if !tx.is_closed() {
-                            tx.blocking_send(Ok(Bytes::new())).unwrap(); // file ended
-                        }
+                        let _ = tx.blocking_send(Ok(Bytes::new())); // file ended
                         break;
                     }
                     // If first stdout read was 0, it was closed without content,
                     // so this check needs to be after EOF check
                     if !did_data_arrive {
                         did_data_arrive = true;
-                        etx.blocking_send(Ok(())).unwrap(); // Signal incoming data
-                    }
-                    if tx.is_closed() {
-                        continue; // We do no have an audience anymore, however we cant really kill the reader either, as that will be under inaccessible permissions
+                        if etx.blocking_send(Ok(())).is_err() {
+                            client_left = true; // Signal incoming data, nobody is waiting for it
+                            break;
+                        }
                     }
                     let res = tx.blocking_send(Ok(buf[0..sz].to_vec().into()));
-                    if let Err(_e) = res {
-                        // Send failed, reader disconnected most likely.
-                        //let res = command.kill();
-                        //error!("Reader closed; kill outcome: {:?}", res);
-                        warn!("HTTP client disconnected unexpectedly!");
-                        std::mem::drop(stdout); // this forces the helper to crash on stdout close
+                    if res.is_err() {
+                        // Send failed, reader disconnected.
+                        client_left = true;
                         break;
                     }
                 }
             }
         }
+        // In case the helper is still writing, this makes it fail with a broken pipe
+        std::mem::drop(stdout);
         let mut outp = Vec::new();
         loop {
             let mut buf = [0u8; BUFFER_SIZE / 2];
@@ -135,60 +339,168 @@ async fn run_helper(input: Vec<u8>) -> Result<mpsc::Receiver<Result<Bytes, SimuE
                         continue;
                     }
                     error!("Failed to read stderr from the helper! {}", e);
-                    return;
+                    break;
                 }
                 Ok(sz) => {
                     if sz == 0 {
                         break;
                     }
-                    for byte in buf.iter().take(sz).copied() {
-                        outp.push(byte);
-                    }
+                    outp.extend_from_slice(&buf[0..sz]);
                 }
             }
         }
         if !outp.is_empty() {
             let errmsg = String::from_utf8(outp);
             match errmsg {
-                Ok(str_) => warn!("Additional stderr output from helper: '{}'", str_),
+                Ok(str_) => {
+                    let rest = log_sandbox_status(&str_);
+                    if !rest.is_empty() {
+                        warn!("Additional stderr output from helper: '{}'", rest);
+                    }
+                }
                 Err(_) => error!("Failed to parse stderr output from helper!"),
             }
         }
         let res = command.wait();
+        let code = match res {
+            Err(_) => {
+                error!("Process start failed!");
+                ReturnCode::Unknown
+            }
+            Ok(status) => ReturnCode::from(status),
+        };
+        drop(permit);
+        if client_left || code == ReturnCode::Cancelled {
+            warn!("HTTP client disconnected, helper cancelled ({:?})", code);
+            metrics::HELPERS_CANCELLED.inc();
+        }
         if did_data_arrive {
             // We already sent data and communicated preliminary success,
             // so the errors are hard to make an use of here.
             return;
         }
-        match res {
+        let _ = match code {
+            ReturnCode::Success => etx.blocking_send(Ok(())), // Empty output
+            code => etx.blocking_send(Err(code)),
+        };
+    });
+    // Written after the output is being read, which waits for the helper however this goes
+    let request = request.to_bytes();
+    let written = task::spawn_blocking(move || {
+        let mut stdin = stdin;
+        if let Err(e) = stdin.write_all(request.as_bytes()) {
+            error!("Failed to write info to helper! {}", e);
+        }
+        stdin
+    })
+    .await;
+    let mut stdin = match written {
+        Ok(stdin) => stdin,
+        Err(e) => {
+            error!("Writing to the helper failed! {}", e);
+            return Err(SimuError::unknown());
+        }
+    };
+    if let Some(body) = body {
+        stdin = send_body(stdin, body).await?;
+    }
+    let first_output = match (
+        as_duration(timeouts().auth),
+        as_duration(timeouts().first_byte),
+    ) {
+        (Some(auth), Some(first_byte)) => match timeout(auth + first_byte, erx.recv()).await {
+            Ok(v) => v,
             Err(_) => {
-                error!("Process start failed!");
-                etx.blocking_send(Err(ReturnCode::Unknown)).unwrap(); // Process start failed
+                warn!("Helper did not produce any output in time, giving up");
+                Some(Err(ReturnCode::Timeout))
             }
-            Ok(status) => {
-                if status.code() != Some(0) {
-                    etx.blocking_send(Err(ReturnCode::from(status))).unwrap();
-                }
-            }
-        }
-    });
-    match erx.recv().await {
+        },
+        _ => erx.recv().await,
+    };
+    match first_output {
         Some(v) => match v {
-            Ok(_) => Ok(rx),
+            Ok(_) => Ok(HelperOutput {
+                rx,
+                _stdin: stdin,
+                idle: as_duration(timeouts().idle).map(|d| Box::pin(sleep(d))),
+            }),
             Err(rc) => Err(SimuError::new(rc)),
         },
         None => Err(SimuError::unknown()),
     }
 }
 
-fn build_partial_input(usern: &str, passw: &str, path: &str) -> Vec<u8> {
-    let mut input: Vec<u8> = usern.as_bytes().to_vec();
-    input.push(0);
+/**
+ * Passes the body of an upload on to the helper as it arrives.
+ *
+ * A helper that stops reading it has failed, which is left for its exit code to tell.
+ * The body failing, or stalling for the idle timeout, drops stdin so the helper removes what it stored so far.
+ */
+async fn send_body(mut stdin: ChildStdin, mut body: Body) -> Result<ChildStdin, SimuError> {
+    loop {
+        let next = match as_duration(timeouts().idle) {
+            Some(idle) => match timeout(idle, body.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    warn!("Upload idle for too long, giving up");
+                    return Err(SimuError::new(ReturnCode::Timeout));
+                }
+            },
+            None => body.next().await,
+        };
+        let chunk = match next {
+            Some(Ok(chunk)) if chunk.is_empty() => continue,
+            Some(Ok(chunk)) => chunk,
+            Some(Err(err)) => return Err(err),
+            None => Bytes::new(), // Ends the body
+        };
+        let end = chunk.is_empty();
+        let (returned, res) = task::spawn_blocking(move || {
+            let res = write_body_chunk(&mut stdin, &chunk);
+            (stdin, res)
+        })
+        .await
+        .map_err(|_| SimuError::unknown())?;
+        stdin = returned;
+        if res.is_err() || end {
+            return Ok(stdin);
+        }
+    }
+}
 
-    input.extend(passw.as_bytes().iter().copied());
-    input.push(0);
+/**
+ * Logs the line the helper reports once it has confined itself, returning the rest of its output.
+ * Missing protections are warned about only once, as they stay missing until the kernel changes.
+ */
+fn log_sandbox_status(output: &str) -> String {
+    let mut rest = String::new();
+    for line in output.split_inclusive('\n') {
+        match line.strip_prefix("sandbox: ") {
+            Some(status) => {
+                let status = status.trim_end();
+                if status.contains("=off") && !SANDBOX_WARNED.swap(true, Ordering::Relaxed) {
+                    warn!("Helper sandbox is incomplete on this kernel: {}", status);
+                } else {
+                    debug!("Helper sandbox: {}", status);
+                }
+            }
+            None => rest.push_str(line),
+        }
+    }
+    rest
+}
 
-    input.extend(path.as_bytes().iter().copied());
-    input.push(0);
-    input
+fn build_request(
+    credentials: Credentials,
+    share: &str,
+    path: &[u8],
+    operation: Operation,
+) -> HelperRequest {
+    HelperRequest {
+        credentials,
+        share: share.to_owned(),
+        path: path.to_owned(),
+        operation,
+        timeouts: timeouts(),
+    }
 }
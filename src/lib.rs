@@ -1,29 +1,519 @@
-use std::fs::DirEntry;
+use std::fs::{DirEntry, FileType};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileTypeExt;
 use std::process::ExitStatus;
 
 use serde::{Deserialize, Serialize};
 
+use crate::secret::Secret;
+
+pub mod config;
+pub mod secret;
+
+/**
+ * Names are kept as the raw bytes from the filesystem, which need not be valid UTF-8.
+ */
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct DirectoryEntry {
-    name: String,
-    is_dir: bool,
+    pub name: Vec<u8>,
+    pub is_dir: bool,
+    pub kind: EntryKind,
+    pub link_target: Option<Vec<u8>>,
+    /// Only regular files and directories can be downloaded or browsed
+    pub downloadable: bool,
+    pub permissions: Permissions,
+}
+
+/**
+ * What the user listing a directory may do with an entry, as the kernel decides it for them.
+ * Entries that are symbolic links not followed can't be read, written or executed through simu.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    /// For directories, entering them
+    pub execute: bool,
+    /// Removing or renaming the entry, which depends on the directory it is in
+    pub delete: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+    Unknown,
+}
+
+impl From<FileType> for EntryKind {
+    fn from(ft: FileType) -> Self {
+        if ft.is_file() {
+            Self::File
+        } else if ft.is_dir() {
+            Self::Directory
+        } else if ft.is_symlink() {
+            Self::Symlink
+        } else if ft.is_fifo() {
+            Self::Fifo
+        } else if ft.is_socket() {
+            Self::Socket
+        } else if ft.is_char_device() {
+            Self::CharDevice
+        } else if ft.is_block_device() {
+            Self::BlockDevice
+        } else {
+            Self::Unknown
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct Directory(pub Vec<DirectoryEntry>);
-
-impl From<DirEntry> for DirectoryEntry {
-    fn from(dir: DirEntry) -> Self {
-        let mut name = dir.file_name().to_string_lossy().to_owned().to_string();
-        let is_dir = dir.file_type().unwrap().is_dir();
-        if is_dir {
-            name += "/";
+pub struct Directory {
+    pub entries: Vec<DirectoryEntry>,
+    /// Whether the user may create entries in the directory itself
+    pub modifiable: bool,
+}
+
+impl DirectoryEntry {
+    /**
+     * Describes an entry of a directory being listed.
+     * Symbolic links are described by what they point to only if `follow_link` is set,
+     * as decided by the share's symlink policy.
+     */
+    pub fn new(dir: &DirEntry, follow_link: bool, permissions: Permissions) -> Self {
+        let name = dir.file_name().into_vec();
+        let file_type = dir.file_type().unwrap();
+        let kind = EntryKind::from(file_type);
+        let (link_target, target_type) = if kind == EntryKind::Symlink {
+            let link_target = std::fs::read_link(dir.path())
+                .ok()
+                .map(|target| target.into_os_string().into_vec());
+            let target_type = if follow_link {
+                std::fs::metadata(dir.path())
+                    .ok()
+                    .map(|meta| meta.file_type())
+            } else {
+                None
+            };
+            (link_target, target_type)
+        } else {
+            (None, Some(file_type))
+        };
+        let is_dir = target_type.map(|t| t.is_dir()).unwrap_or(false);
+        let downloadable = target_type
+            .map(|t| t.is_dir() || t.is_file())
+            .unwrap_or(false);
+        Self {
+            name,
+            is_dir,
+            kind,
+            link_target,
+            downloadable,
+            permissions,
+        }
+    }
+
+    /**
+     * Describes a share in the top level listing.
+     */
+    pub fn share(name: &str) -> Self {
+        Self {
+            name: name.as_bytes().to_vec(),
+            is_dir: true,
+            kind: EntryKind::Directory,
+            link_target: None,
+            downloadable: true,
+            // Whatever the user can do within it, the share itself stays
+            permissions: Permissions {
+                read: true,
+                write: false,
+                execute: true,
+                delete: false,
+            },
         }
-        Self { name, is_dir }
     }
 }
 
-#[derive(Debug)]
+const MAX_REQUEST_SIZE: usize = 65536;
+/// Largest chunk of an upload's body sent to the helper at once
+const MAX_CHUNK_SIZE: usize = 65536;
+
+/**
+ * Everything the helper needs to know to serve a request.
+ * Sent over the helper's stdin, prefixed with its length.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct HelperRequest {
+    pub credentials: Credentials,
+    pub share: String,
+    /// Path within the share, as raw bytes
+    pub path: Vec<u8>,
+    pub operation: Operation,
+    pub timeouts: Timeouts,
+}
+
+/**
+ * How the user proves who they are.
+ *
+ * Anything but a password is only taken from the server account configured in `[server]`,
+ * as the helper has to trust the server to have verified it.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Credentials {
+    /// Checked by PAM
+    Password { username: String, password: Secret },
+    /// DER encoded certificate the client presented and the server verified during the TLS handshake,
+    /// mapped to an account by the helper
+    ClientCertificate(Vec<u8>),
+    /// Username a trusted reverse proxy logged in, passed on in a header
+    RemoteUser(String),
+    /// Personal API token, checked against the helper's own store
+    Token(Secret),
+    /// Shared link, checked against the helper's own store, logging in as its creator.
+    /// The request's share and path are ignored, its path is taken as relative to the linked one
+    Link {
+        id: String,
+        password: Option<Secret>,
+        /// Whether a download or upload counts against the link's limits, which HEAD requests don't
+        counted: bool,
+    },
+    /// No credentials at all, for shares served to anyone as the account configured for them
+    Anonymous,
+    /// Request signed with an SSH key from the user's `authorized_keys`
+    SshSignature {
+        username: String,
+        request: SignedRequest,
+        /// In the format of `ssh-keygen -Y sign`, without the armor
+        signature: Vec<u8>,
+    },
+}
+
+/// Namespace of SSH signatures over requests, as given to `ssh-keygen -Y sign -n`
+pub const SSH_SIGNATURE_NAMESPACE: &str = "simu";
+/// How far the `Date` of a signed request may be from ours, in seconds
+pub const SSH_SIGNATURE_MAX_AGE: u64 = 300;
+
+/**
+ * The parts of a request that are signed with an SSH key.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SignedRequest {
+    pub method: String,
+    /// Path and query as sent, still percent-encoded
+    pub path: String,
+    /// From the `Date` header
+    pub date: String,
+    /// Hex encoded SHA-256 of the body, from the `X-Content-SHA256` header
+    pub content_sha256: String,
+}
+
+impl SignedRequest {
+    /**
+     * What is signed, each part on its own line.
+     */
+    pub fn message(&self) -> Vec<u8> {
+        format!(
+            "{}\n{}\n{}\n{}",
+            self.method, self.path, self.date, self.content_sha256
+        )
+        .into_bytes()
+    }
+
+    /**
+     * Whether the request was signed recently, so a captured one can't be replayed for long.
+     */
+    pub fn is_fresh(&self) -> bool {
+        let date = match httpdate::parse_http_date(&self.date) {
+            Ok(date) => date,
+            Err(_) => return false,
+        };
+        let now = std::time::SystemTime::now();
+        let age = now
+            .duration_since(date)
+            .or_else(|_| date.duration_since(now))
+            .map(|age| age.as_secs())
+            .unwrap_or(u64::MAX);
+        age <= SSH_SIGNATURE_MAX_AGE
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Operation {
+    ReadFile,
+    ReadDir,
+    /// Managing the user's API tokens, not tied to a share
+    Tokens(TokenOperation),
+    /// Changing the user's password to this one, logged in with the current one.
+    /// Outputs a `PasswordChange`
+    ChangePassword(Secret),
+    /// Managing the user's shared links
+    Links(LinkOperation),
+    /// Storing the body that follows the request as a file at the path,
+    /// owned by the group if one is given. Outputs an `UploadedFile`
+    Upload {
+        group: Option<String>,
+    },
+    /// Creating a directory at the path, owned by the group if one is given
+    MakeDir {
+        group: Option<String>,
+    },
+    /// Changing the mode, group or modification time of what is at the path
+    SetAttributes(Attributes),
+    /// Only checking the credentials, before the server lists the shares. Outputs nothing
+    Login,
+}
+
+impl Operation {
+    /**
+     * Whether the operation leaves everything as it was.
+     */
+    pub fn is_read(&self) -> bool {
+        matches!(self, Self::ReadFile | Self::ReadDir)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum TokenOperation {
+    /// Outputs a `MintedToken`
+    Mint(NewToken),
+    /// Outputs the user's tokens as a `Vec<TokenInfo>`
+    List,
+    /// Revokes the token with this id
+    Revoke(String),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct NewToken {
+    pub name: String,
+    /// Unix time after which the token is refused
+    pub expires: Option<u64>,
+    pub scope: TokenScope,
+}
+
+/**
+ * What an API token may be used for, beyond what its owner can do anyway.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct TokenScope {
+    /// Only operations that change nothing
+    #[serde(default)]
+    pub read_only: bool,
+    /// Only this share
+    pub share: Option<String>,
+    /// Only this path and what is below it, within the share
+    pub path_prefix: Option<String>,
+}
+
+/**
+ * An API token as shown to its owner, without the secret part.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct TokenInfo {
+    pub id: String,
+    pub name: String,
+    /// Unix times
+    pub created: u64,
+    pub expires: Option<u64>,
+    pub last_used: Option<u64>,
+    pub scope: TokenScope,
+}
+
+/**
+ * A freshly minted API token, the only time the whole token is known.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct MintedToken {
+    pub token: String,
+    pub info: TokenInfo,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum LinkOperation {
+    /// Outputs the new link's `LinkInfo`
+    Create(NewLink),
+    /// Outputs the user's links as a `Vec<LinkInfo>`
+    List,
+    /// Revokes the link with this id
+    Revoke(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewLink {
+    pub share: String,
+    /// Within the share
+    pub path: String,
+    /// Unix time after which the link is refused
+    pub expires: u64,
+    pub max_downloads: Option<u64>,
+    /// Asked of visitors, if set
+    pub password: Option<Secret>,
+    /// Makes the link one to upload into the linked directory, instead of downloading from it
+    pub drop_box: Option<DropBox>,
+}
+
+/**
+ * What may be uploaded through a drop box, a link that takes files without showing what is there.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct DropBox {
+    /// In bytes
+    pub max_file_size: u64,
+    /// File name extensions accepted, without the dot, any if empty
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    pub max_files: Option<u64>,
+}
+
+/**
+ * A shared link as shown to its creator.
+ * The id is what visitors need to know, together with the server's signature of it.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct LinkInfo {
+    pub id: String,
+    pub share: String,
+    pub path: String,
+    /// Unix times
+    pub created: u64,
+    pub expires: u64,
+    pub max_downloads: Option<u64>,
+    pub downloads: u64,
+    pub has_password: bool,
+    /// Files uploaded to a drop box, attempts included
+    #[serde(default)]
+    pub uploads: u64,
+    #[serde(default)]
+    pub drop_box: Option<DropBox>,
+}
+
+/**
+ * A file the helper stored, under the name it was stored as,
+ * which drop boxes change to not replace another.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct UploadedFile {
+    pub name: Vec<u8>,
+    pub size: u64,
+}
+
+/**
+ * Changes to the attributes of a file or directory, those left None stay as they are.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Attributes {
+    /// Permission bits, including the set-user-ID, set-group-ID and sticky bits
+    pub mode: Option<u32>,
+    /// Name of the group to give it to
+    pub group: Option<String>,
+    /// Modification time, as seconds and nanoseconds since the Unix epoch
+    pub modified: Option<(i64, u32)>,
+}
+
+/**
+ * The outcome of changing a password, with what PAM had to say about the new one.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct PasswordChange {
+    pub changed: bool,
+    pub messages: Vec<String>,
+}
+
+/**
+ * Timeouts in seconds for the phases of a helper's run, 0 disables a timeout.
+ * Configured in the `[timeouts]` section, and passed on to the helper with each request.
+ */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Authenticating the user with PAM
+    pub auth: u64,
+    /// From switching the user to the first byte of output
+    pub first_byte: u64,
+    /// Between any two writes of output
+    pub idle: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            auth: 30,
+            first_byte: 60,
+            idle: 60,
+        }
+    }
+}
+
+impl HelperRequest {
+    /**
+     * The request prefixed with its length, held as a secret as it can contain a password.
+     */
+    pub fn to_bytes(&self) -> Secret {
+        let len = bincode::serialized_size(self).expect("HelperRequest is always serializable");
+        let mut bytes = Secret::zeroed(4 + len as usize);
+        bytes.as_bytes_mut()[..4].copy_from_slice(&(len as u32).to_le_bytes());
+        bincode::serialize_into(&mut bytes.as_bytes_mut()[4..], self)
+            .expect("HelperRequest is always serializable");
+        bytes
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Option<Self> {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).ok()?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_REQUEST_SIZE {
+            return None; // Anyone can run the helper, don't let them make it allocate arbitrarily
+        }
+        let mut body = Secret::zeroed(len);
+        reader.read_exact(body.as_bytes_mut()).ok()?;
+        bincode::deserialize(body.as_bytes()).ok()
+    }
+}
+
+/**
+ * Writes part of an upload's body, which follows the request on the helper's stdin
+ * in chunks prefixed with their length. Writing nothing ends the body.
+ */
+pub fn write_body_chunk<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return writer.write_all(&0u32.to_le_bytes());
+    }
+    for chunk in data.chunks(MAX_CHUNK_SIZE) {
+        writer.write_all(&(chunk.len() as u32).to_le_bytes())?;
+        writer.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+/**
+ * Reads the next chunk of an upload's body, None once it has ended.
+ * The stream closing before that fails with `UnexpectedEof`.
+ */
+pub fn read_body_chunk<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len == 0 {
+        return Ok(None);
+    }
+    if len > MAX_CHUNK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "chunk too large",
+        ));
+    }
+    let mut chunk = vec![0u8; len];
+    reader.read_exact(&mut chunk)?;
+    Ok(Some(chunk))
+}
+
+#[derive(Debug, PartialEq)]
 pub enum ReturnCode {
     Success = 0,
 
@@ -32,6 +522,21 @@ pub enum ReturnCode {
     LoginFailed = 2,
     UnexpectedType = 3,
     PermissionDenied = 4,
+    Cancelled = 5,
+    Timeout = 6,
+    SpecialFile = 7,
+    SymlinkDenied = 8,
+    AccessDenied = 9,
+    /// Too many helpers running, only produced by the server
+    Busy = 10,
+    /// The request asked for something that can't be done as asked
+    InvalidInput = 11,
+    /// The password was right, but has expired and must be changed before logging in
+    PasswordExpired = 12,
+    /// An upload was larger than allowed
+    TooLarge = 13,
+    /// Something to be created already exists
+    AlreadyExists = 14,
 
     // Errors from outside
     SignalTerm = 99,
@@ -46,6 +551,16 @@ impl From<ExitStatus> for ReturnCode {
             2 => Self::LoginFailed,
             3 => Self::UnexpectedType,
             4 => Self::PermissionDenied,
+            5 => Self::Cancelled,
+            6 => Self::Timeout,
+            7 => Self::SpecialFile,
+            8 => Self::SymlinkDenied,
+            9 => Self::AccessDenied,
+            10 => Self::Busy,
+            11 => Self::InvalidInput,
+            12 => Self::PasswordExpired,
+            13 => Self::TooLarge,
+            14 => Self::AlreadyExists,
             101 => Self::Panic,
             99 => Self::SignalTerm,
             0 => Self::Success,
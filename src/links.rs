@@ -0,0 +1,271 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::Header;
+use actix_web::http::Method;
+use actix_web::ResponseError;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_httpauth::extractors::basic;
+use actix_web_httpauth::extractors::AuthenticationError;
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use percent_encoding::{percent_decode_str, percent_encode};
+use serde::{Deserialize, Serialize};
+use simu::config::Config;
+use simu::secret::Secret;
+use simu::{Credentials, DropBox, LinkInfo, LinkOperation, NewLink, ReturnCode};
+
+use crate::error::SimuError;
+use crate::file_service::{error_response, serve_dir, serve_file, NAME_ENCODE_SET};
+use crate::login::Attempt;
+use crate::security::ServerKey;
+use crate::throttle::Throttle;
+use crate::{helper, login, security};
+
+const PREFIX: &str = "/.simu/l/";
+pub const DROP_BOX_PREFIX: &str = "/.simu/d/";
+/// One week
+const DEFAULT_EXPIRES_IN: u64 = 604800;
+
+/**
+ * A link to create, as sent by the client.
+ */
+#[derive(Deserialize)]
+pub struct CreateRequest {
+    share: String,
+    path: String,
+    /// Seconds from now until the link expires
+    expires_in: Option<u64>,
+    max_downloads: Option<u64>,
+    password: Option<Secret>,
+    drop_box: Option<DropBox>,
+}
+
+/**
+ * A link as shown to its creator, with the URL to hand out.
+ */
+#[derive(Serialize)]
+struct SharedLink {
+    url: String,
+    #[serde(flatten)]
+    info: LinkInfo,
+}
+
+impl SharedLink {
+    /**
+     * The URL is signed, so links that were never created are turned away without running the helper.
+     * It ends with the name of what is linked, as requests to the link are relative to the directory it is in.
+     * Drop boxes have URLs of their own, signed differently so neither kind can be used as the other.
+     */
+    fn new(info: LinkInfo, key: &ServerKey) -> Self {
+        if info.drop_box.is_some() {
+            let signature = hex::encode(key.sign(&[b"drop-box", info.id.as_bytes()]));
+            let url = format!("{}{}.{}", DROP_BOX_PREFIX, info.id, signature);
+            return Self { url, info };
+        }
+        let signature = hex::encode(key.sign(&[b"share-link", info.id.as_bytes()]));
+        let name = info
+            .path
+            .trim_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or_default();
+        let url = format!(
+            "{}{}.{}/{}",
+            PREFIX,
+            info.id,
+            signature,
+            percent_encode(name.as_bytes(), NAME_ENCODE_SET)
+        );
+        Self { url, info }
+    }
+}
+
+#[derive(Serialize)]
+struct LinkList {
+    /// Needed to create and revoke links with Basic credentials
+    csrf_token: Option<String>,
+    links: Vec<SharedLink>,
+}
+
+/**
+ * Lists the user's shared links.
+ */
+pub async fn list(
+    req: HttpRequest,
+    key: web::Data<ServerKey>,
+    throttle: web::Data<Throttle>,
+) -> HttpResponse {
+    let (credentials, attempt) = match login::credentials(&req, &throttle) {
+        Ok(login) => login,
+        Err(resp) => return *resp,
+    };
+    let result = helper::run_links::<Vec<LinkInfo>>(credentials, LinkOperation::List).await;
+    attempt.record(&throttle, &result);
+    match result {
+        Ok(links) => HttpResponse::Ok().json(LinkList {
+            csrf_token: security::csrf_token(&req),
+            links: links
+                .into_iter()
+                .map(|info| SharedLink::new(info, &key))
+                .collect(),
+        }),
+        Err(err) => error_response(&err, &req),
+    }
+}
+
+/**
+ * Creates a link to a file or directory, for anyone who gets its URL to download,
+ * or a drop box for them to upload into a directory.
+ */
+pub async fn create(
+    req: HttpRequest,
+    body: web::Json<CreateRequest>,
+    config: web::Data<Config>,
+    key: web::Data<ServerKey>,
+    throttle: web::Data<Throttle>,
+) -> HttpResponse {
+    // Links signed with a key that goes away on restart would all stop working with it
+    if !key.is_persistent() {
+        return HttpResponse::ServiceUnavailable()
+            .body("Shared links need SIMU_SECRET_KEY_FILE to be set");
+    }
+    let body = body.into_inner();
+    if config.share(&body.share).is_none() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (credentials, attempt) = match login::credentials(&req, &throttle) {
+        Ok(login) => login,
+        Err(resp) => return *resp,
+    };
+    let new = NewLink {
+        share: body.share,
+        path: body.path,
+        expires: now.saturating_add(body.expires_in.unwrap_or(DEFAULT_EXPIRES_IN)),
+        max_downloads: body.max_downloads,
+        password: body.password,
+        drop_box: body.drop_box,
+    };
+    let result = helper::run_links::<LinkInfo>(credentials, LinkOperation::Create(new)).await;
+    attempt.record(&throttle, &result);
+    match result {
+        Ok(info) => HttpResponse::Created().json(SharedLink::new(info, &key)),
+        Err(err) => error_response(&err, &req),
+    }
+}
+
+pub async fn revoke(
+    req: HttpRequest,
+    id: web::Path<String>,
+    throttle: web::Data<Throttle>,
+) -> HttpResponse {
+    let (credentials, attempt) = match login::credentials(&req, &throttle) {
+        Ok(login) => login,
+        Err(resp) => return *resp,
+    };
+    let operation = LinkOperation::Revoke(id.into_inner());
+    let result = helper::run_links::<()>(credentials, operation).await;
+    attempt.record(&throttle, &result);
+    match result {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(&err, &req),
+    }
+}
+
+/**
+ * Serves a visit to a shared link, as its creator, without logging in.
+ * Passwords of links are asked for with Basic authentication, any username will do.
+ */
+pub async fn visit(
+    req: HttpRequest,
+    key: web::Data<ServerKey>,
+    throttle: web::Data<Throttle>,
+) -> HttpResponse {
+    let rest = req.path().strip_prefix(PREFIX).unwrap_or_default();
+    let (link, path) = match rest.split_once('/') {
+        Some(split) => split,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let id = match verify(&key, b"share-link", link) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let path: Vec<u8> = percent_decode_str(path).collect();
+    if path.contains(&0) {
+        return HttpResponse::NotFound().finish();
+    }
+    let (credentials, attempt) = match credentials(&req, &throttle, id) {
+        Ok(login) => login,
+        Err(resp) => return *resp,
+    };
+
+    let resp = if path.is_empty() || path.ends_with(b"/") {
+        let urlpath: Vec<u8> = percent_decode_str(req.path()).collect();
+        serve_dir(credentials, &req, "", &path, &urlpath, false).await
+    } else {
+        serve_file(credentials, &req, "", &path).await
+    };
+    attempt.record(&throttle, &resp);
+    match resp {
+        Ok(resp) => resp,
+        Err(err) => visit_error(&err, &req),
+    }
+}
+
+/**
+ * The id of a link from the start of its URL, `<id>.<signature>`, if the server signed it as `kind`.
+ */
+pub fn verify<'a>(key: &ServerKey, kind: &[u8], link: &'a str) -> Option<&'a str> {
+    let (id, signature) = link.split_once('.')?;
+    let signature = hex::decode(signature).ok()?;
+    if key.verify(&[kind, id.as_bytes()], &signature) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/**
+ * Credentials for visiting the link, with its password if the visitor gave one.
+ */
+pub fn credentials(
+    req: &HttpRequest,
+    throttle: &Throttle,
+    id: &str,
+) -> Result<(Credentials, Attempt), Box<HttpResponse>> {
+    let client_ip = throttle.client_ip(req);
+    // Throttled per link, as that is what the password belongs to
+    let user_id = format!("link:{}", id);
+    let password = Authorization::<Basic>::parse(req)
+        .ok()
+        .and_then(|auth| auth.as_ref().password().map(|p| Secret::new(p.as_bytes())));
+    if password.is_some() {
+        if let Some(wait) = throttle.check(client_ip, &user_id) {
+            return Err(Box::new(
+                HttpResponse::TooManyRequests()
+                    .append_header(("Retry-After", (wait.as_secs() + 1).to_string()))
+                    .finish(),
+            ));
+        }
+    }
+    let attempt = Attempt::new(client_ip, password.as_ref().map(|_| user_id));
+    let credentials = Credentials::Link {
+        id: id.to_string(),
+        password,
+        counted: req.method() != Method::HEAD,
+    };
+    Ok((credentials, attempt))
+}
+
+/**
+ * Like `error_response`, but asks for the link's password when it was missing or wrong.
+ */
+pub fn visit_error(err: &SimuError, req: &HttpRequest) -> HttpResponse {
+    if err.code == ReturnCode::LoginFailed {
+        let config = basic::Config::default().realm("Shared link");
+        return AuthenticationError::from(config).error_response();
+    }
+    error_response(err, req)
+}
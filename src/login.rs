@@ -0,0 +1,141 @@
+use std::net::IpAddr;
+
+use actix_web::http::header::{Header, AUTHORIZATION};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use actix_web_httpauth::extractors::basic;
+use actix_web_httpauth::extractors::AuthenticationError;
+use actix_web_httpauth::headers::authorization::{Authorization, Basic, Bearer};
+use simu::secret::Secret;
+use simu::{Credentials, ReturnCode};
+
+use crate::error::SimuError;
+use crate::remote_user::RemoteUser;
+use crate::ssh_auth;
+use crate::throttle::Throttle;
+use crate::tls::ClientCertificate;
+
+/**
+ * A login attempt, remembered to record its outcome.
+ */
+pub struct Attempt {
+    client_ip: Option<IpAddr>,
+    /// Only credentials that can be guessed are throttled
+    user_id: Option<String>,
+}
+
+/**
+ * Picks the credentials of a request, for the helper to check.
+ *
+ * A proxy's login stands for the whole request, otherwise the `Authorization` header takes precedence,
+ * so a browser holding a certificate can still log in as someone else.
+ * Fails with the response to send instead, asking for credentials or telling the client to wait.
+ */
+pub fn credentials(
+    req: &HttpRequest,
+    throttle: &Throttle,
+) -> Result<(Credentials, Attempt), Box<HttpResponse>> {
+    let client_ip = throttle.client_ip(req);
+    let remote_user = req
+        .app_data::<web::Data<RemoteUser>>()
+        .and_then(|remote_user| remote_user.user(req.peer_addr(), req.headers()));
+    if let Some(user) = remote_user {
+        let attempt = Attempt {
+            client_ip,
+            user_id: None,
+        };
+        return Ok((Credentials::RemoteUser(user.to_string()), attempt));
+    }
+    if let Ok(auth) = Authorization::<Basic>::parse(req) {
+        let auth: &Basic = auth.as_ref();
+        let password = match auth.password() {
+            Some(password) => password,
+            None => return Err(Box::new(HttpResponse::Unauthorized().finish())),
+        };
+        if let Some(wait) = throttle.check(client_ip, auth.user_id()) {
+            return Err(Box::new(
+                HttpResponse::TooManyRequests()
+                    .append_header(("Retry-After", (wait.as_secs() + 1).to_string()))
+                    .finish(),
+            ));
+        }
+        let credentials = Credentials::Password {
+            username: auth.user_id().to_string(),
+            password: Secret::new(password.as_bytes()),
+        };
+        let attempt = Attempt {
+            client_ip,
+            user_id: Some(auth.user_id().to_string()),
+        };
+        return Ok((credentials, attempt));
+    }
+    let attempt = Attempt {
+        client_ip,
+        user_id: None,
+    };
+    // Tokens are too long to guess, signatures, certificates and proxy logins can't be guessed at all
+    if ssh_auth::is_signed(req) {
+        return match ssh_auth::credentials(req) {
+            Some(credentials) => Ok((credentials, attempt)),
+            None => Err(Box::new(HttpResponse::Unauthorized().finish())),
+        };
+    }
+    if let Ok(auth) = Authorization::<Bearer>::parse(req) {
+        let token: &Bearer = auth.as_ref();
+        return Ok((
+            Credentials::Token(Secret::new(token.token().as_bytes())),
+            attempt,
+        ));
+    }
+    if let Some(cert) = req.conn_data::<ClientCertificate>() {
+        return Ok((Credentials::ClientCertificate(cert.0.clone()), attempt));
+    }
+    Err(Box::new(ask_for_credentials(req)))
+}
+
+/**
+ * Like `credentials`, for shares open to anonymous visitors.
+ * Requests without any credentials are let in as such, those with credentials still log in with them.
+ */
+pub fn credentials_or_anonymous(
+    req: &HttpRequest,
+    throttle: &Throttle,
+) -> Result<(Credentials, Attempt), Box<HttpResponse>> {
+    match credentials(req, throttle) {
+        Err(_) if req.headers().get(AUTHORIZATION).is_none() => {
+            let attempt = Attempt::new(throttle.client_ip(req), None);
+            Ok((Credentials::Anonymous, attempt))
+        }
+        login => login,
+    }
+}
+
+impl Attempt {
+    /**
+     * An attempt with credentials other than a user's, throttled if `user_id` is set.
+     */
+    pub fn new(client_ip: Option<IpAddr>, user_id: Option<String>) -> Self {
+        Self { client_ip, user_id }
+    }
+
+    pub fn record<T>(&self, throttle: &Throttle, result: &Result<T, SimuError>) {
+        let user_id = match &self.user_id {
+            Some(user_id) => user_id,
+            None => return,
+        };
+        match result {
+            Err(err) if err.code == ReturnCode::LoginFailed => {
+                throttle.record_failure(self.client_ip, user_id)
+            }
+            Ok(_) => throttle.record_success(user_id),
+            Err(_) => {}
+        }
+    }
+}
+
+/**
+ * The usual Basic authentication challenge, for requests without any credentials.
+ */
+pub fn ask_for_credentials(req: &HttpRequest) -> HttpResponse {
+    let config = req.app_data::<basic::Config>().cloned().unwrap_or_default();
+    AuthenticationError::from(config).error_response()
+}
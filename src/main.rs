@@ -1,18 +1,36 @@
+use std::sync::Arc;
+
 use actix_web::body::BoxBody;
 use actix_web::dev::ServiceResponse;
-use actix_web::http::header::ContentType;
+use actix_web::http::header::{self, ContentType};
 use actix_web::http::StatusCode;
 use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers};
 use actix_web::{web, App, HttpResponse, HttpServer};
 use actix_web_httpauth::extractors::basic::Config;
-use actix_web_httpauth::middleware::HttpAuthentication;
 use handlebars::Handlebars;
 use serde::Serialize;
+use simu::config;
 use tracing::{error, info};
 
+mod attributes;
+mod content;
+mod drop_box;
 mod error;
 mod file_service;
 mod helper;
+mod links;
+mod login;
+mod metrics;
+mod multipart;
+mod password;
+mod privileges;
+mod remote_user;
+mod security;
+mod ssh_auth;
+mod throttle;
+mod tls;
+mod tokens;
+mod upload;
 
 fn err_handler<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<BoxBody>> {
     let req = res.request();
@@ -29,7 +47,7 @@ fn err_handler<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResp
         error: &'a str,
     }
 
-    let resp = match hb {
+    let mut resp = match hb {
         Some(hb) => {
             let data = ErrData {
                 status_code: res.status().as_u16(),
@@ -48,6 +66,12 @@ fn err_handler<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResp
         }
         None => fallback_error(),
     };
+    // Keep headers such as Retry-After, except those describing the replaced body
+    for (name, value) in res.headers() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            resp.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
     Ok(ErrorHandlerResponse::Response(ServiceResponse::new(
         res.into_parts().0,
         resp.map_into_left_body(),
@@ -56,37 +80,106 @@ fn err_handler<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResp
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("sign") {
+        std::process::exit(ssh_auth::sign_command(&args[2..]));
+    }
     tracing_subscriber::fmt::init();
+    let config = match config::Config::load() {
+        Ok(config) => web::Data::new(config),
+        Err(e) => panic!("Invalid configuration: {}", e),
+    };
+    let service_user = privileges::ServiceUser::from_config(&config.server);
+    privileges::refuse_root(service_user.as_ref());
 
     let mut handlebars = Handlebars::new();
     handlebars
         .register_templates_directory(".html", get_templates_dir())
         .unwrap();
     let handlebars_ref = web::Data::new(handlebars);
+    helper::configure(&config.limits, config.timeouts);
+    let throttle = web::Data::new(throttle::Throttle::from_env());
+    let server_key = web::Data::new(security::ServerKey::from_env());
+    let remote_user = web::Data::new(remote_user::RemoteUser::from_env());
+    let metrics_peers = web::Data::new(metrics::MetricsPeers::from_env());
+    let (proto, addr) = get_bind_uri();
+    let is_tls = proto == "tls";
 
     let server = HttpServer::new(move || {
         App::new()
             .app_data(Config::default().realm("Restricted area"))
             .app_data(handlebars_ref.clone())
+            .app_data(config.clone())
+            .app_data(throttle.clone())
+            .app_data(server_key.clone())
+            .app_data(remote_user.clone())
+            .app_data(metrics_peers.clone())
+            .wrap(ssh_auth::VerifyBody)
+            .wrap(security::Csrf)
             .wrap(
                 ErrorHandlers::new()
+                    .handler(StatusCode::BAD_REQUEST, err_handler)
                     .handler(StatusCode::NOT_FOUND, err_handler)
                     .handler(StatusCode::UNAUTHORIZED, err_handler)
                     .handler(StatusCode::FORBIDDEN, err_handler)
-                    .handler(StatusCode::INTERNAL_SERVER_ERROR, err_handler),
+                    .handler(StatusCode::METHOD_NOT_ALLOWED, err_handler)
+                    .handler(StatusCode::CONFLICT, err_handler)
+                    .handler(StatusCode::PAYLOAD_TOO_LARGE, err_handler)
+                    .handler(StatusCode::TOO_MANY_REQUESTS, err_handler)
+                    .handler(StatusCode::INTERNAL_SERVER_ERROR, err_handler)
+                    .handler(StatusCode::SERVICE_UNAVAILABLE, err_handler)
+                    .handler(StatusCode::GATEWAY_TIMEOUT, err_handler),
+            )
+            .wrap(security::default_headers(is_tls))
+            .route("/.simu/metrics", web::get().to(metrics::serve_metrics))
+            .service(
+                web::resource("/.simu/tokens")
+                    .route(web::get().to(tokens::list))
+                    .route(web::post().to(tokens::mint)),
+            )
+            .route("/.simu/tokens/{id}", web::delete().to(tokens::revoke))
+            .service(
+                web::resource("/.simu/links")
+                    .route(web::get().to(links::list))
+                    .route(web::post().to(links::create)),
+            )
+            .route("/.simu/links/{id}", web::delete().to(links::revoke))
+            .route("/.simu/l/{tail:.*}", web::get().to(links::visit))
+            .service(
+                web::resource("/.simu/d/{link}")
+                    .route(web::get().to(drop_box::page))
+                    .route(web::post().to(drop_box::upload_form)),
+            )
+            .route("/.simu/d/{link}/{name}", web::put().to(drop_box::upload))
+            .service(
+                web::resource("/.simu/password")
+                    .route(web::get().to(password::form))
+                    .route(web::post().to(password::change)),
             )
-            .wrap(HttpAuthentication::basic(|req, _creds| async { Ok(req) }))
             .default_service(web::route().to(file_service::serve_files))
-    });
-    let (proto, addr) = get_bind_uri();
+    })
+    .on_connect(tls::on_connect);
     info!("Binding to {} {}", proto, addr);
     let server = if proto == "unix" {
-        server.bind_uds(addr)?
+        let server = server.bind_uds(&addr)?;
+        if let Some(user) = &service_user {
+            user.give_socket(&addr);
+        }
+        server
     } else if proto == "tcp" {
         server.bind(addr)?
+    } else if proto == "tls" {
+        // Read while still privileged, reloads need the files to stay readable to the [server] user
+        let certificates = Arc::new(tls::Certificates::from_env());
+        tls::reload_on_hangup(certificates.clone());
+        server.bind_rustls(addr, tls::server_config(certificates))?
     } else {
         panic!("Unknown protocol passed to SIMU_BIND!");
     };
+    // Bound, so privileged ports and socket paths are no longer a reason to be root
+    if let Some(user) = &service_user {
+        privileges::drop_privileges(user);
+    }
     server.run().await
 }
 
@@ -0,0 +1,76 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+
+use crate::throttle::Proxy;
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub static HELPERS_STARTED: Counter =
+    Counter::new("simu_helpers_started_total", "Helper processes started");
+pub static HELPERS_CANCELLED: Counter = Counter::new(
+    "simu_helpers_cancelled_total",
+    "Helper processes cancelled due to the HTTP client disconnecting",
+);
+
+pub static HELPERS_REJECTED: Counter = Counter::new(
+    "simu_helpers_rejected_total",
+    "Requests refused as too many helper processes were running",
+);
+
+/**
+ * The peers allowed to read the metrics, from `SIMU_METRICS_PEERS`.
+ * None by default, as anyone in front of a reverse proxy would share its address.
+ */
+pub struct MetricsPeers(Vec<Proxy>);
+
+impl MetricsPeers {
+    pub fn from_env() -> Self {
+        Self(Proxy::list_from_env("SIMU_METRICS_PEERS"))
+    }
+}
+
+static COUNTERS: &[&Counter] = &[&HELPERS_STARTED, &HELPERS_CANCELLED, &HELPERS_REJECTED];
+
+/**
+ * Renders all counters in the Prometheus text exposition format, for allowed peers only.
+ */
+pub async fn serve_metrics(req: HttpRequest, peers: web::Data<MetricsPeers>) -> impl Responder {
+    if !peers.0.contains(&Proxy::of(req.peer_addr())) {
+        return HttpResponse::NotFound().finish();
+    }
+    let mut body = String::new();
+    for counter in COUNTERS {
+        let _ = writeln!(body, "# HELP {} {}", counter.name, counter.help);
+        let _ = writeln!(body, "# TYPE {} counter", counter.name);
+        let _ = writeln!(
+            body,
+            "{} {}",
+            counter.name,
+            counter.value.load(Ordering::Relaxed)
+        );
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(body)
+}
@@ -0,0 +1,363 @@
+use actix_web::dev::Payload;
+use actix_web::error::PayloadError;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::HttpRequest;
+use futures::StreamExt;
+
+/// Most bytes read while looking for the file, be it in other fields or its headers
+const MAX_SKIPPED: usize = 65536;
+
+/**
+ * The first file of a `multipart/form-data` body, read as it arrives.
+ *
+ * Upload forms have a single file field, so fields before it are kept as text and anything after it is ignored.
+ */
+pub struct FilePart {
+    payload: Payload,
+    /// Names and values of the fields before the file
+    fields: Vec<(String, String)>,
+    /// `\r\n--` and the boundary, which ends every part
+    delimiter: Vec<u8>,
+    buf: BytesMut,
+    done: bool,
+}
+
+#[derive(Debug)]
+pub enum MultipartError {
+    /// Not a multipart body, or not one with a file in it
+    Malformed,
+    /// Reading the body failed
+    Payload,
+}
+
+impl From<PayloadError> for MultipartError {
+    fn from(_: PayloadError) -> Self {
+        Self::Payload
+    }
+}
+
+impl FilePart {
+    /**
+     * Reads up to the contents of the first file, returning the name the client gave it.
+     */
+    pub async fn open(
+        req: &HttpRequest,
+        payload: Payload,
+    ) -> Result<(Vec<u8>, Self), MultipartError> {
+        let boundary = boundary(req).ok_or(MultipartError::Malformed)?;
+        let mut part = Self {
+            payload,
+            fields: Vec::new(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // So the first boundary, at the very start, looks like the others
+            buf: BytesMut::from(&b"\r\n"[..]),
+            done: false,
+        };
+        let mut skipped = 0;
+        // The field whose value comes before the next boundary, none for the preamble
+        let mut field = None;
+        loop {
+            // Past the next boundary, collecting the value before it
+            let mut value = Vec::new();
+            let start = loop {
+                if let Some(pos) = find(&part.buf, &part.delimiter) {
+                    value.extend_from_slice(&part.buf[..pos]);
+                    break pos + part.delimiter.len();
+                }
+                let keep = part.delimiter.len();
+                if part.buf.len() > keep {
+                    skipped += part.buf.len() - keep;
+                    value.extend_from_slice(&part.buf.split_to(part.buf.len() - keep));
+                }
+                part.fill(&mut skipped).await?;
+            };
+            skipped += start;
+            let _ = part.buf.split_to(start);
+            if let Some(name) = field.take() {
+                let value = String::from_utf8_lossy(&value).into_owned();
+                part.fields.push((name, value));
+            }
+            // The headers of the part, after the line break ending the boundary
+            let end = loop {
+                if let Some(pos) = find(&part.buf, b"\r\n\r\n") {
+                    break pos;
+                }
+                if part.buf.starts_with(b"--") {
+                    return Err(MultipartError::Malformed); // The last boundary, no file found
+                }
+                part.fill(&mut skipped).await?;
+            };
+            let headers = part.buf.split_to(end + 4);
+            skipped += headers.len();
+            // Also when everything arrived without needing to read more
+            if skipped > MAX_SKIPPED {
+                return Err(MultipartError::Malformed);
+            }
+            if let Some(name) = param(&headers, b"filename=\"") {
+                return Ok((name, part));
+            }
+            field = param(&headers, b"; name=\"")
+                .map(|name| String::from_utf8_lossy(&name).into_owned());
+        }
+    }
+
+    /**
+     * The value of a field before the file, forms put those they want read first.
+     */
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /**
+     * The next piece of the file's contents, None once it has ended.
+     */
+    pub async fn next_chunk(&mut self) -> Option<Result<Bytes, MultipartError>> {
+        if self.done {
+            return None;
+        }
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                self.done = true;
+                let chunk = self.buf.split_to(pos).freeze();
+                return if chunk.is_empty() {
+                    None
+                } else {
+                    Some(Ok(chunk))
+                };
+            }
+            // Anything that can't be the start of the delimiter is part of the file
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let chunk = self.buf.split_to(self.buf.len() - keep).freeze();
+                return Some(Ok(chunk));
+            }
+            match self.payload.next().await {
+                Some(Ok(bytes)) => self.buf.extend_from_slice(&bytes),
+                Some(Err(err)) => return Some(Err(err.into())),
+                None => return Some(Err(MultipartError::Malformed)),
+            }
+        }
+    }
+
+    /**
+     * Reads more of the body while looking for the file.
+     */
+    async fn fill(&mut self, skipped: &mut usize) -> Result<(), MultipartError> {
+        if *skipped + self.buf.len() > MAX_SKIPPED {
+            return Err(MultipartError::Malformed);
+        }
+        match self.payload.next().await {
+            Some(Ok(bytes)) => {
+                self.buf.extend_from_slice(&bytes);
+                Ok(())
+            }
+            Some(Err(err)) => Err(err.into()),
+            None => Err(MultipartError::Malformed),
+        }
+    }
+}
+
+fn boundary(req: &HttpRequest) -> Option<String> {
+    let content_type = req.headers().get(CONTENT_TYPE)?.to_str().ok()?;
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    let boundary = params.find_map(|param| {
+        let (name, value) = param.trim().split_once('=')?;
+        if name.eq_ignore_ascii_case("boundary") {
+            Some(value.trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })?;
+    // RFC 2046 limits boundaries to 70 characters
+    if boundary.is_empty() || boundary.len() > 70 {
+        return None;
+    }
+    Some(boundary)
+}
+
+/**
+ * A quoted parameter of a part's `Content-Disposition`, such as `filename="`, as the raw bytes browsers send.
+ */
+fn param(headers: &[u8], prefix: &[u8]) -> Option<Vec<u8>> {
+    headers
+        .split(|b| *b == b'\n')
+        .filter(|line| line.len() > 20 && line[..20].eq_ignore_ascii_case(b"content-disposition:"))
+        .find_map(|line| {
+            let start = find(line, prefix)? + prefix.len();
+            let len = line[start..].iter().position(|b| *b == b'"')?;
+            Some(line[start..start + len].to_vec())
+        })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+
+    use actix_web::test::TestRequest;
+    use futures::Stream;
+
+    use super::*;
+
+    const CONTENT_TYPE_VALUE: &str = "multipart/form-data; boundary=XyZ";
+
+    /**
+     * A request with the body arriving in these chunks.
+     */
+    fn request(chunks: &[&[u8]]) -> (HttpRequest, Payload) {
+        let chunks: Vec<Result<Bytes, PayloadError>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+            Box::pin(futures::stream::iter(chunks));
+        let (req, _) = TestRequest::post()
+            .insert_header((CONTENT_TYPE, CONTENT_TYPE_VALUE))
+            .to_http_parts();
+        (req, Payload::from(stream))
+    }
+
+    /**
+     * Reads the file in the body, split into chunks at these offsets.
+     */
+    async fn read(body: &[u8], splits: &[usize]) -> Result<(Vec<u8>, Vec<u8>), MultipartError> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for &split in splits {
+            chunks.push(&body[start..split]);
+            start = split;
+        }
+        chunks.push(&body[start..]);
+        let (req, payload) = request(&chunks);
+        let (name, mut part) = FilePart::open(&req, payload).await?;
+        let mut contents = Vec::new();
+        while let Some(chunk) = part.next_chunk().await {
+            contents.extend_from_slice(&chunk?);
+        }
+        Ok((name, contents))
+    }
+
+    fn body(contents: &[u8]) -> Vec<u8> {
+        [
+            &b"--XyZ\r\nContent-Disposition: form-data; name=\"group\"\r\n\r\nstaff\r\n"[..],
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n",
+            b"Content-Type: text/plain\r\n\r\n",
+            contents,
+            b"\r\n--XyZ--\r\n",
+        ]
+        .concat()
+    }
+
+    #[actix_web::test]
+    async fn reads_fields_and_file() {
+        let (req, payload) = request(&[&body(b"hello")]);
+        let (name, mut part) = FilePart::open(&req, payload).await.unwrap();
+        assert_eq!(name, b"a.txt");
+        assert_eq!(part.field("group"), Some("staff"));
+        assert_eq!(part.field("other"), None);
+        let chunk = part.next_chunk().await.unwrap().unwrap();
+        assert_eq!(&chunk[..], b"hello");
+        assert!(part.next_chunk().await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn finds_boundaries_split_across_chunks() {
+        let contents = b"some contents of the file";
+        let body = body(contents);
+        // Every place a chunk can end, including within each boundary
+        for split in 1..body.len() {
+            let (name, read) = read(&body, &[split]).await.unwrap();
+            assert_eq!(name, b"a.txt", "split at {}", split);
+            assert_eq!(read, contents, "split at {}", split);
+        }
+        let splits: Vec<usize> = (1..body.len()).collect();
+        assert_eq!(read(&body, &splits).await.unwrap().1, contents);
+    }
+
+    #[actix_web::test]
+    async fn keeps_delimiter_prefixes_in_file() {
+        // Starts of the delimiter that don't go on to the whole boundary belong to the file
+        let contents = b"a\r\n--XyA\r\n--Xy\r\n-\r\n\r\n--X\r\n-";
+        let body = body(contents);
+        for split in 1..body.len() {
+            assert_eq!(read(&body, &[split]).await.unwrap().1, &contents[..]);
+        }
+    }
+
+    #[actix_web::test]
+    async fn ends_file_at_delimiter_at_chunk_end() {
+        let contents = b"ends right before the delimiter";
+        let body = body(contents);
+        let end = body.len() - b"\r\n--XyZ--\r\n".len();
+        assert_eq!(read(&body, &[end]).await.unwrap().1, contents);
+        assert_eq!(read(&body, &[end + 2]).await.unwrap().1, contents);
+    }
+
+    #[actix_web::test]
+    async fn refuses_body_without_file() {
+        let body =
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"group\"\r\n\r\nstaff\r\n--XyZ--\r\n";
+        assert!(matches!(
+            read(body, &[]).await,
+            Err(MultipartError::Malformed)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn refuses_file_cut_short() {
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a\"\r\n\r\nnever ends";
+        assert!(matches!(
+            read(body, &[]).await,
+            Err(MultipartError::Malformed)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn stops_looking_for_file_after_max_skipped() {
+        let field = |size: usize| {
+            [
+                &b"--XyZ\r\nContent-Disposition: form-data; name=\"big\"\r\n\r\n"[..],
+                &vec![b'x'; size],
+                b"\r\n",
+            ]
+            .concat()
+        };
+        let file = b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a\"\r\n\r\nhi\r\n--XyZ--\r\n";
+        let small = [field(100), file.to_vec()].concat();
+        let chunks: Vec<usize> = (1..small.len() / 1000).map(|i| i * 1000).collect();
+        assert_eq!(read(&small, &chunks).await.unwrap().1, b"hi");
+        let large = [field(MAX_SKIPPED), file.to_vec()].concat();
+        let chunks: Vec<usize> = (1..large.len() / 1000).map(|i| i * 1000).collect();
+        assert!(matches!(
+            read(&large, &chunks).await,
+            Err(MultipartError::Malformed)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn refuses_other_content_types() {
+        let (req, _) = TestRequest::post()
+            .insert_header((CONTENT_TYPE, "application/x-www-form-urlencoded"))
+            .to_http_parts();
+        assert!(matches!(
+            FilePart::open(&req, Payload::None).await,
+            Err(MultipartError::Malformed)
+        ));
+    }
+}
@@ -0,0 +1,117 @@
+use actix_web::http::header::{ContentType, Header};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use handlebars::Handlebars;
+use serde::{Deserialize, Serialize};
+use simu::secret::Secret;
+use simu::{Credentials, PasswordChange};
+use tracing::error;
+
+use crate::file_service::error_response;
+use crate::throttle::Throttle;
+use crate::{helper, login, security};
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    #[serde(default)]
+    expired: bool,
+}
+
+/**
+ * The form as sent by the browser, the current password being the one it logs in with.
+ */
+#[derive(Deserialize)]
+pub struct ChangeForm {
+    new_password: Secret,
+    confirm_password: Secret,
+}
+
+#[derive(Serialize)]
+struct Page {
+    csrf_token: Option<String>,
+    expired: bool,
+    changed: bool,
+    messages: Vec<String>,
+}
+
+/**
+ * The page to change one's password, where logins with an expired password are sent.
+ * Only for Basic credentials, as the password being changed is the one they hold.
+ */
+pub async fn form(req: HttpRequest, query: web::Query<PageQuery>) -> HttpResponse {
+    if Authorization::<Basic>::parse(&req).is_err() {
+        return login::ask_for_credentials(&req);
+    }
+    render(
+        &req,
+        Page {
+            csrf_token: security::csrf_token(&req),
+            expired: query.expired,
+            changed: false,
+            messages: Vec::new(),
+        },
+    )
+}
+
+/**
+ * Changes the password through PAM, showing what it had to say about the new one.
+ */
+pub async fn change(
+    req: HttpRequest,
+    form: web::Form<ChangeForm>,
+    throttle: web::Data<Throttle>,
+) -> HttpResponse {
+    let (credentials, attempt) = match login::credentials(&req, &throttle) {
+        Ok(login) => login,
+        Err(resp) => return *resp,
+    };
+    if !matches!(credentials, Credentials::Password { .. }) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let form = form.into_inner();
+    if form.new_password.as_bytes() != form.confirm_password.as_bytes() {
+        return render(
+            &req,
+            Page {
+                csrf_token: security::csrf_token(&req),
+                expired: false,
+                changed: false,
+                messages: vec!["The new passwords do not match.".to_string()],
+            },
+        );
+    }
+    let result = helper::run_password(credentials, form.new_password).await;
+    attempt.record(&throttle, &result);
+    match result {
+        Ok(PasswordChange { changed, messages }) => render(
+            &req,
+            Page {
+                // The token is bound to the old password, and useless once it is changed
+                csrf_token: security::csrf_token(&req).filter(|_| !changed),
+                expired: false,
+                changed,
+                messages,
+            },
+        ),
+        Err(err) => error_response(&err, &req),
+    }
+}
+
+fn render(req: &HttpRequest, page: Page) -> HttpResponse {
+    let hb = match req.app_data::<web::Data<Handlebars>>() {
+        Some(hb) => hb,
+        None => {
+            error!("No Handlebars instance found! This is a bug!");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match hb.render("password", &page) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(body),
+        Err(err) => {
+            error!("Failed to apply password template! {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::ffi::CString;
+
+use libc::{chown, geteuid, getpwnam, gid_t, initgroups, setgid, setuid, uid_t};
+use simu::config::Server;
+use tracing::info;
+
+/**
+ * Account the server runs as, from the `[server]` section the helper also trusts it by.
+ * Started as root, the server switches to it once it has bound its sockets.
+ */
+pub struct ServiceUser {
+    name: CString,
+    uid: uid_t,
+    gid: gid_t,
+}
+
+impl ServiceUser {
+    pub fn from_config(server: &Server) -> Option<Self> {
+        let name = server.user.as_deref()?;
+        let name = CString::new(name).expect("The [server] user is malformed!");
+        let pwent = unsafe { getpwnam(name.as_ptr()) };
+        if pwent.is_null() {
+            panic!("The [server] user names an account that does not exist!");
+        }
+        let (uid, gid) = unsafe { ((*pwent).pw_uid, (*pwent).pw_gid) };
+        if uid == 0 {
+            panic!("The [server] user must not be root!");
+        }
+        Some(Self { name, uid, gid })
+    }
+
+    /**
+     * Hands a socket bound while still root over to the service user,
+     * so it keeps working for whoever could connect to it before.
+     */
+    pub fn give_socket(&self, path: &str) {
+        let path = CString::new(path).expect("SIMU_BIND contents are malformed!");
+        if unsafe { geteuid() } == 0 && unsafe { chown(path.as_ptr(), self.uid, self.gid) } < 0 {
+            panic!("Could not hand the socket over to the [server] user!");
+        }
+    }
+}
+
+/**
+ * The server faces the network, and should never need root itself.
+ * Running it as root is refused unless privileges are dropped to the `[server]` user after binding,
+ * or `SIMU_ALLOW_ROOT=1` explicitly allows it.
+ */
+pub fn refuse_root(service_user: Option<&ServiceUser>) {
+    let is_root = unsafe { geteuid() } == 0;
+    let allowed = std::env::var("SIMU_ALLOW_ROOT")
+        .map(|v| v == "1")
+        .unwrap_or(false);
+    if is_root && service_user.is_none() && !allowed {
+        panic!("Refusing to run as root! Set user in the [server] section to drop privileges after binding, or SIMU_ALLOW_ROOT=1 to run as root anyway");
+    }
+}
+
+/**
+ * Switches to the service user, for good.
+ * Started as anyone else, the helper wouldn't trust the logins we checked, so we refuse to run.
+ */
+pub fn drop_privileges(user: &ServiceUser) {
+    if unsafe { geteuid() } != 0 {
+        if unsafe { geteuid() } == user.uid {
+            return; // Already started as the service user
+        }
+        panic!("The server was started as neither root nor the [server] user!");
+    }
+    unsafe {
+        // Groups first, as they can't be changed after giving up root
+        if initgroups(user.name.as_ptr(), user.gid) < 0 || setgid(user.gid) < 0 {
+            panic!("Could not switch groups to the [server] user!");
+        }
+        if setuid(user.uid) < 0 {
+            panic!("Could not switch to the [server] user!");
+        }
+        if setuid(0) == 0 {
+            panic!("We should not be able to become root again!");
+        }
+    }
+    info!("Dropped privileges to user {}", user.name.to_string_lossy());
+}
@@ -0,0 +1,45 @@
+use std::net::SocketAddr;
+
+use actix_web::http::header::{HeaderMap, HeaderName};
+use tracing::debug;
+
+use crate::throttle::Proxy;
+
+/**
+ * Logins done by a reverse proxy in front of us, such as NGINX with `auth_request`,
+ * passed on as the username in the header named by `SIMU_REMOTE_USER_HEADER`.
+ *
+ * The header is only taken from peers listed in `SIMU_REMOTE_USER_PROXIES`,
+ * anyone else could simply send it themselves.
+ */
+pub struct RemoteUser {
+    header: Option<HeaderName>,
+    proxies: Vec<Proxy>,
+}
+
+impl RemoteUser {
+    pub fn from_env() -> Self {
+        let header = std::env::var("SIMU_REMOTE_USER_HEADER").ok().map(|name| {
+            HeaderName::try_from(name).expect("SIMU_REMOTE_USER_HEADER is not a header name!")
+        });
+        let proxies = Proxy::list_from_env("SIMU_REMOTE_USER_PROXIES");
+        if header.is_some() && proxies.is_empty() {
+            panic!(
+                "SIMU_REMOTE_USER_HEADER is set, but SIMU_REMOTE_USER_PROXIES lists no proxies!"
+            );
+        }
+        Self { header, proxies }
+    }
+
+    /**
+     * The username the proxy vouches for, if the request came from a trusted proxy.
+     */
+    pub fn user<'a>(&self, peer: Option<SocketAddr>, headers: &'a HeaderMap) -> Option<&'a str> {
+        let value = headers.get(self.header.as_ref()?)?;
+        if !self.proxies.contains(&Proxy::of(peer)) {
+            debug!("Ignoring remote user header from untrusted peer {:?}", peer);
+            return None;
+        }
+        value.to_str().ok().filter(|user| !user.is_empty())
+    }
+}
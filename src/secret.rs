@@ -0,0 +1,108 @@
+use std::ffi::CStr;
+use std::fmt;
+
+use libc::{c_void, mlock, munlock};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+
+/**
+ * A credential such as a password.
+ *
+ * Held in a buffer that is locked in memory, so it can't be swapped out, and overwritten when dropped.
+ * It is never reallocated, so no stray copies are left behind as it is used,
+ * and it shows up as redacted when printed.
+ */
+pub struct Secret {
+    /// The secret followed by a NUL byte, so it can be handed to C as is
+    buf: Box<[u8]>,
+}
+
+impl Secret {
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut secret = Self::zeroed(bytes.len());
+        secret.as_bytes_mut().copy_from_slice(bytes);
+        secret
+    }
+
+    /**
+     * A secret of `len` zero bytes, to be filled in place.
+     */
+    pub fn zeroed(len: usize) -> Self {
+        let buf = vec![0u8; len + 1].into_boxed_slice();
+        // Best effort, fails if RLIMIT_MEMLOCK is exhausted
+        unsafe {
+            mlock(buf.as_ptr() as *const c_void, buf.len());
+        }
+        Self { buf }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.buf.len() - 1]
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let len = self.buf.len() - 1;
+        &mut self.buf[..len]
+    }
+
+    /**
+     * None if the secret contains a NUL byte, which a C string can't hold.
+     */
+    pub fn as_c_str(&self) -> Option<&CStr> {
+        CStr::from_bytes_with_nul(&self.buf).ok()
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.buf.zeroize();
+        // Locks don't stack, this may unlock a page shared with another secret a bit early
+        unsafe {
+            munlock(self.buf.as_ptr() as *const c_void, self.buf.len());
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str("Secret([redacted])")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.as_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(SecretVisitor)
+    }
+}
+
+struct SecretVisitor;
+
+impl<'de> Visitor<'de> for SecretVisitor {
+    type Value = Secret;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("bytes of a secret")
+    }
+
+    /// Formats without a notion of bytes, such as URL encoded forms, hand over strings
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Secret, E> {
+        Ok(Secret::new(v.as_bytes()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Secret, E> {
+        Ok(Secret::new(v))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, mut v: Vec<u8>) -> Result<Secret, E> {
+        let secret = Secret::new(&v);
+        v.zeroize();
+        Ok(secret)
+    }
+}
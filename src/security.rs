@@ -0,0 +1,300 @@
+use std::future::{ready, Ready};
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{Header, AUTHORIZATION};
+use actix_web::http::Method;
+use actix_web::middleware::DefaultHeaders;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use futures::future::LocalBoxFuture;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::links::DROP_BOX_PREFIX;
+use crate::remote_user::RemoteUser;
+use crate::tls::ClientCertificate;
+
+/// Policy for the pages we render ourselves, served files get a stricter one in `content`
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; style-src 'self' 'unsafe-inline'; \
+    img-src 'self' data:; form-action 'self'; frame-ancestors 'none'; base-uri 'none'";
+/// One year
+const HSTS_DEFAULT_MAX_AGE: u64 = 31536000;
+const PERMISSIONS_POLICY: &str =
+    "camera=(), microphone=(), geolocation=(), payment=(), usb=(), interest-cohort=()";
+
+lazy_static! {
+    /// Origin browsers reach us at, such as `https://simu.example.com`, when a reverse proxy
+    /// makes the connection look otherwise
+    static ref PUBLIC_ORIGIN: Option<String> = std::env::var("SIMU_PUBLIC_ORIGIN")
+        .ok()
+        .map(|origin| origin.trim_end_matches('/').to_owned());
+}
+
+/**
+ * Key for signing anything we hand out and later need to trust again.
+ */
+pub struct ServerKey {
+    key: [u8; 32],
+    /// Whether the key outlives the server, as shared links have to
+    persistent: bool,
+}
+
+impl ServerKey {
+    /**
+     * Reads the key from the file named by `SIMU_SECRET_KEY_FILE`,
+     * or generates one valid until the server is restarted.
+     */
+    pub fn from_env() -> Self {
+        let mut key = [0u8; 32];
+        let persistent = match std::env::var("SIMU_SECRET_KEY_FILE") {
+            Ok(path) => {
+                let contents = std::fs::read(&path)
+                    .unwrap_or_else(|e| panic!("Cannot read SIMU_SECRET_KEY_FILE: {}", e));
+                if contents.len() < key.len() {
+                    panic!("SIMU_SECRET_KEY_FILE must contain at least 32 bytes!");
+                }
+                // Any length is fine, as long as it has enough entropy
+                key.copy_from_slice(
+                    &Hmac::<Sha256>::new_from_slice(&contents)
+                        .expect("HMAC takes keys of any length")
+                        .finalize()
+                        .into_bytes(),
+                );
+                true
+            }
+            Err(_) => {
+                warn!("SIMU_SECRET_KEY_FILE not set, using a key valid until restart, shared links can't be created");
+                rand::rngs::OsRng.fill_bytes(&mut key);
+                false
+            }
+        };
+        Self { key, persistent }
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+
+    pub fn sign(&self, parts: &[&[u8]]) -> [u8; 32] {
+        self.mac(parts).finalize().into_bytes().into()
+    }
+
+    /**
+     * Checks a signature made by `sign`, in constant time.
+     */
+    pub fn verify(&self, parts: &[&[u8]], signature: &[u8]) -> bool {
+        self.mac(parts).verify_slice(signature).is_ok()
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        for part in parts {
+            // Length prefixed, so parts can't be shifted between each other
+            mac.update(&(part.len() as u64).to_le_bytes());
+            mac.update(part);
+        }
+        mac
+    }
+}
+
+/**
+ * Headers sent with every response, unless a handler already set them.
+ * HSTS is sent on `tls:` binds, or behind a TLS terminating proxy if `SIMU_HSTS_MAX_AGE` is set.
+ */
+pub fn default_headers(tls: bool) -> DefaultHeaders {
+    let headers = DefaultHeaders::new()
+        .add(("Content-Security-Policy", CONTENT_SECURITY_POLICY))
+        .add(("Referrer-Policy", "same-origin"))
+        .add(("X-Frame-Options", "DENY"))
+        .add(("X-Content-Type-Options", "nosniff"))
+        .add(("Permissions-Policy", PERMISSIONS_POLICY));
+    let max_age: u64 = match std::env::var("SIMU_HSTS_MAX_AGE") {
+        Ok(max_age) => max_age
+            .parse()
+            .expect("SIMU_HSTS_MAX_AGE is not a number of seconds!"),
+        Err(_) if tls => HSTS_DEFAULT_MAX_AGE,
+        Err(_) => 0,
+    };
+    if max_age == 0 {
+        headers
+    } else {
+        headers.add(("Strict-Transport-Security", format!("max-age={}", max_age)))
+    }
+}
+
+/**
+ * Token to include in state-changing requests made with Basic credentials or a client certificate,
+ * either as the `X-CSRF-Token` header or the `csrf_token` query parameter.
+ *
+ * Those credentials are our session, so the token is bound to both the username and the password,
+ * or to the certificate.
+ */
+pub fn csrf_token(req: &HttpRequest) -> Option<String> {
+    let key = req.app_data::<web::Data<ServerKey>>()?;
+    let remote_user = req
+        .app_data::<web::Data<RemoteUser>>()
+        .and_then(|remote_user| remote_user.user(req.peer_addr(), req.headers()));
+    with_session(req, remote_user, req.conn_data(), |parts| {
+        hex::encode(key.sign(parts))
+    })
+}
+
+/**
+ * Calls `f` with what identifies the session, if the browser sends along credentials by itself,
+ * picked in the same order as when serving files.
+ * A proxy's login is usually kept in a cookie, so it counts as such too.
+ */
+fn with_session<M: HttpMessage, T>(
+    req: &M,
+    remote_user: Option<&str>,
+    cert: Option<&ClientCertificate>,
+    f: impl FnOnce(&[&[u8]]) -> T,
+) -> Option<T> {
+    if let Some(user) = remote_user {
+        return Some(f(&[b"csrf-remote-user", user.as_bytes()]));
+    }
+    if let Ok(auth) = Authorization::<Basic>::parse(req) {
+        let auth: &Basic = auth.as_ref();
+        return Some(f(&[
+            b"csrf",
+            auth.user_id().as_bytes(),
+            auth.password().map(|p| p.as_bytes()).unwrap_or_default(),
+        ]));
+    }
+    // Other kinds of authorization, such as API tokens, are never sent by the browser on its own
+    if req.headers().contains_key(AUTHORIZATION) {
+        return None;
+    }
+    let cert = cert?;
+    Some(f(&[b"csrf-certificate", &cert.0]))
+}
+
+/**
+ * Rejects state-changing requests that may have been made by another site on a visitor's behalf.
+ *
+ * All methods other than GET, HEAD and OPTIONS need an `Origin` or `Referer` from our own origin
+ * if either is present, and a valid CSRF token if the browser sent Basic credentials along.
+ */
+pub struct Csrf;
+
+impl<S, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware { service }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Err(reason) = check_csrf(&req) {
+            warn!(
+                "Refused possible cross-site {} request to {}: {}",
+                req.method(),
+                req.path(),
+                reason
+            );
+            let (req, _) = req.into_parts();
+            let res = HttpResponse::Forbidden().finish();
+            return Box::pin(async { Ok(ServiceResponse::new(req, res)) });
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_boxed_body) })
+    }
+}
+
+fn check_csrf(req: &ServiceRequest) -> Result<(), &'static str> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+    let own_origin = match &*PUBLIC_ORIGIN {
+        Some(origin) => origin.clone(),
+        None => {
+            let info = req.connection_info();
+            format!("{}://{}", info.scheme(), info.host())
+        }
+    };
+    let headers = req.headers();
+    if let Some(origin) = headers.get("Origin") {
+        if origin.to_str().ok() != Some(own_origin.as_str()) {
+            return Err("foreign Origin");
+        }
+    } else if let Some(referer) = headers.get("Referer") {
+        let referer = referer.to_str().map_err(|_| "malformed Referer")?;
+        let same_origin = referer
+            .strip_prefix(own_origin.as_str())
+            .map(|rest| rest.is_empty() || rest.starts_with('/'))
+            .unwrap_or(false);
+        if !same_origin {
+            return Err("foreign Referer");
+        }
+    }
+
+    // Browsers resend Basic credentials, certificates and proxy cookies on their own,
+    // other kinds need no token.
+    // Drop boxes are exempt, their pages can't have a token before the link's password is given,
+    // and their signed URLs can only be used for uploading to them anyway
+    if req.path().starts_with(DROP_BOX_PREFIX) {
+        return Ok(());
+    }
+    let remote_user = req
+        .app_data::<web::Data<RemoteUser>>()
+        .and_then(|remote_user| remote_user.user(req.peer_addr(), req.headers()));
+    if with_session(req, remote_user, req.conn_data(), |_| ()).is_none() {
+        return Ok(());
+    }
+    let key = req
+        .app_data::<web::Data<ServerKey>>()
+        .ok_or("no server key")?;
+    let token = headers
+        .get("X-CSRF-Token")
+        .and_then(|token| token.to_str().ok())
+        .map(str::to_owned)
+        .or_else(|| {
+            web::Query::<CsrfQuery>::from_query(req.query_string())
+                .ok()
+                .map(|query| query.into_inner().csrf_token)
+        })
+        .ok_or("missing CSRF token")?;
+    let token = hex::decode(token).map_err(|_| "malformed CSRF token")?;
+    if with_session(req, remote_user, req.conn_data(), |parts| {
+        key.verify(parts, &token)
+    }) == Some(true)
+    {
+        Ok(())
+    } else {
+        Err("invalid CSRF token")
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CsrfQuery {
+    csrf_token: String,
+}
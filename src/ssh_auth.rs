@@ -0,0 +1,270 @@
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::process::{Command, Stdio};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use futures::Stream;
+use sha2::{Digest, Sha256};
+use simu::{Credentials, SignedRequest, SSH_SIGNATURE_NAMESPACE};
+
+const SCHEME: &str = "SSH-Signature";
+const CONTENT_SHA256: &str = "X-Content-SHA256";
+
+/**
+ * Whether the request claims to be signed with an SSH key,
+ * as `Authorization: SSH-Signature user="alice", signature="<base64>"`.
+ */
+pub fn is_signed<M: HttpMessage>(req: &M) -> bool {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with(SCHEME))
+        .unwrap_or(false)
+}
+
+/**
+ * The signature of a signed request, for the helper to check against the user's `authorized_keys`.
+ * None if it is malformed, or too old to be worth checking.
+ *
+ * The method, path with query, `Date` and `X-Content-SHA256` headers are what is signed,
+ * the body itself is checked against the latter by `VerifyBody` as it is read.
+ */
+pub fn credentials(req: &HttpRequest) -> Option<Credentials> {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let params = header(AUTHORIZATION.as_str())?.strip_prefix(SCHEME)?;
+    let (mut user, mut signature) = (None, None);
+    for param in params.split(',') {
+        let (name, value) = param.trim().split_once('=')?;
+        let value = value.strip_prefix('"')?.strip_suffix('"')?;
+        match name {
+            "user" => user = Some(value),
+            "signature" => signature = Some(STANDARD.decode(value).ok()?),
+            _ => return None,
+        }
+    }
+    let content_sha256 = header(CONTENT_SHA256)?;
+    if hex::decode(content_sha256).ok()?.len() != 32 {
+        return None;
+    }
+    let request = SignedRequest {
+        method: req.method().to_string(),
+        path: req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/")
+            .to_string(),
+        date: header("Date")?.to_string(),
+        content_sha256: content_sha256.to_ascii_lowercase(),
+    };
+    if !request.is_fresh() {
+        return None;
+    }
+    Some(Credentials::SshSignature {
+        username: user?.to_string(),
+        request,
+        signature: signature?,
+    })
+}
+
+/**
+ * Checks the body of signed requests against their `X-Content-SHA256` header.
+ *
+ * Reading the body fails at its end if it doesn't match, so handlers that read it
+ * in full before acting never act on a body that wasn't signed.
+ */
+pub struct VerifyBody;
+
+impl<S, B> Transform<S, ServiceRequest> for VerifyBody
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = VerifyBodyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(VerifyBodyMiddleware { service }))
+    }
+}
+
+pub struct VerifyBodyMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for VerifyBodyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        if is_signed(&req) {
+            // A missing or malformed digest fails the login instead
+            let expected = req
+                .headers()
+                .get(CONTENT_SHA256)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| hex::decode(v).ok());
+            if let Some(expected) = expected {
+                let payload = HashedPayload {
+                    inner: req.take_payload(),
+                    hasher: Some(Sha256::new()),
+                    expected,
+                };
+                let payload: Pin<Box<dyn Stream<Item = _>>> = Box::pin(payload);
+                req.set_payload(Payload::from(payload));
+            }
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_boxed_body) })
+    }
+}
+
+struct HashedPayload {
+    inner: Payload,
+    /// Taken once the body has ended
+    hasher: Option<Sha256>,
+    expected: Vec<u8>,
+}
+
+impl Stream for HashedPayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(bytes))) => {
+                if let Some(hasher) = &mut self.hasher {
+                    hasher.update(&bytes);
+                }
+                Poll::Ready(Some(Ok(bytes)))
+            }
+            Poll::Ready(None) => {
+                let matches = match self.hasher.take() {
+                    Some(hasher) => hasher.finalize()[..] == self.expected[..],
+                    None => true,
+                };
+                if matches {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some(Err(PayloadError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "body does not match X-Content-SHA256",
+                    )))))
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+/**
+ * `simu sign [-k KEY] [-u USER] METHOD URL [BODY_FILE]`
+ *
+ * Signs a request with `ssh-keygen`, and prints the headers to send it with,
+ * in the format `curl -H @FILE` takes.
+ */
+pub fn sign_command(args: &[String]) -> i32 {
+    let mut key = std::env::var("HOME")
+        .map(|home| format!("{}/.ssh/id_ed25519", home))
+        .unwrap_or_default();
+    let mut user = std::env::var("USER").unwrap_or_default();
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-k" => key = args.next().cloned().unwrap_or_default(),
+            "-u" => user = args.next().cloned().unwrap_or_default(),
+            _ => positional.push(arg),
+        }
+    }
+    let (method, url, body_file) = match positional[..] {
+        [method, url] => (method, url, None),
+        [method, url, body_file] => (method, url, Some(body_file)),
+        _ => {
+            eprintln!("Usage: simu sign [-k KEY] [-u USER] METHOD URL [BODY_FILE]");
+            return 2;
+        }
+    };
+    let body = match body_file.map(std::fs::read).transpose() {
+        Ok(body) => body.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("Cannot read the body: {}", e);
+            return 1;
+        }
+    };
+    let request = SignedRequest {
+        method: method.to_ascii_uppercase(),
+        path: path_of(url).to_string(),
+        date: httpdate::fmt_http_date(SystemTime::now()),
+        content_sha256: hex::encode(Sha256::digest(&body)),
+    };
+    let signature = match ssh_keygen_sign(&key, &request.message()) {
+        Ok(signature) => signature,
+        Err(e) => {
+            eprintln!("Cannot sign with {}: {}", key, e);
+            return 1;
+        }
+    };
+    println!("Date: {}", request.date);
+    println!("{}: {}", CONTENT_SHA256, request.content_sha256);
+    println!(
+        "Authorization: {} user=\"{}\", signature=\"{}\"",
+        SCHEME, user, signature
+    );
+    0
+}
+
+/**
+ * The path and query of a URL, as the server will see them.
+ */
+fn path_of(url: &str) -> &str {
+    let url = url.split('#').next().unwrap_or_default();
+    let after_scheme = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    match after_scheme.find('/') {
+        Some(start) => &after_scheme[start..],
+        None => "/",
+    }
+}
+
+/**
+ * Signs with `ssh-keygen -Y sign`, which also works with keys held by an agent,
+ * returning the signature without its armor.
+ */
+fn ssh_keygen_sign(key: &str, message: &[u8]) -> io::Result<String> {
+    let mut child = Command::new("ssh-keygen")
+        .args(["-q", "-Y", "sign", "-f", key, "-n", SSH_SIGNATURE_NAMESPACE])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(message)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "ssh-keygen failed to sign",
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect())
+}
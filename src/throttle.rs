@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::HttpRequest;
+use tracing::warn;
+
+/// Entries are pruned once a map grows past this, and it never holds more
+const MAX_TRACKED: usize = 10000;
+
+/**
+ * Tracks failed logins per client address and per username,
+ * locking either out for exponentially growing periods once they fail too often.
+ *
+ * Every request runs PAM, so without this the server would be an unthrottled password oracle.
+ * The price is that anyone can lock an account out by failing to log in to it,
+ * which is what guards against guessing from many addresses at once.
+ */
+pub struct Throttle {
+    settings: Settings,
+    trusted_proxies: Vec<Proxy>,
+    by_ip: Mutex<HashMap<IpAddr, Failures>>,
+    by_user: Mutex<HashMap<String, Failures>>,
+}
+
+struct Settings {
+    /// Failures allowed before lockouts begin
+    max_failures: u32,
+    /// Length of the first lockout, doubled for each further failure
+    lockout: Duration,
+    /// Upper bound for a single lockout, also how long failures are remembered
+    max_lockout: Duration,
+}
+
+#[derive(PartialEq)]
+pub enum Proxy {
+    /// Peers connecting over an UNIX domain socket
+    Unix,
+    Ip(IpAddr),
+}
+
+impl Proxy {
+    /**
+     * Parses a comma-separated list of addresses, with `unix` for UNIX domain socket peers.
+     */
+    pub fn list_from_env(var: &str) -> Vec<Self> {
+        std::env::var(var)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                if s == "unix" {
+                    Self::Unix
+                } else {
+                    Self::Ip(
+                        s.parse()
+                            .unwrap_or_else(|_| panic!("{} contents are malformed!", var)),
+                    )
+                }
+            })
+            .collect()
+    }
+
+    pub fn of(peer: Option<SocketAddr>) -> Self {
+        match peer {
+            Some(addr) => Self::Ip(addr.ip()),
+            None => Self::Unix,
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn from_env() -> Self {
+        let trusted_proxies = Proxy::list_from_env("SIMU_TRUSTED_PROXIES");
+        Self {
+            settings: Settings {
+                max_failures: get_number("SIMU_LOGIN_MAX_FAILURES", 5) as u32,
+                lockout: Duration::from_secs(get_number("SIMU_LOGIN_LOCKOUT", 2)),
+                max_lockout: Duration::from_secs(get_number("SIMU_LOGIN_MAX_LOCKOUT", 3600)),
+            },
+            trusted_proxies,
+            by_ip: Mutex::new(HashMap::new()),
+            by_user: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * Address of the client, taken from `X-Forwarded-For` if the request came through a trusted proxy.
+     * None for requests over UNIX domain sockets without a trusted proxy in front.
+     */
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = Proxy::of(req.peer_addr());
+        if !self.trusted_proxies.contains(&peer) {
+            return match peer {
+                Proxy::Ip(ip) => Some(ip),
+                Proxy::Unix => None,
+            };
+        }
+        // Walk the chain from the nearest hop, the first untrusted one is the client
+        let forwarded: Vec<IpAddr> = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect();
+        let mut client = match peer {
+            Proxy::Ip(ip) => Some(ip),
+            Proxy::Unix => None,
+        };
+        for hop in forwarded.into_iter().rev() {
+            client = Some(hop);
+            if !self.trusted_proxies.contains(&Proxy::Ip(hop)) {
+                break;
+            }
+        }
+        client
+    }
+
+    /**
+     * Returns how long the client or user still has to wait, if either is locked out.
+     */
+    pub fn check(&self, ip: Option<IpAddr>, username: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let by_ip = ip.and_then(|ip| remaining(&self.by_ip, &ip, now));
+        let by_user = remaining(&self.by_user, username, now);
+        let wait = by_ip.max(by_user);
+        if wait.is_some() {
+            warn!(
+                "Login throttled for user={:?} from={}",
+                username,
+                display_ip(ip)
+            );
+        }
+        wait
+    }
+
+    pub fn record_failure(&self, ip: Option<IpAddr>, username: &str) {
+        // Stable format for log watchers such as fail2ban, see examples/fail2ban
+        warn!(
+            "Authentication failure for user={:?} from={}",
+            username,
+            display_ip(ip)
+        );
+        let now = Instant::now();
+        if let Some(ip) = ip {
+            fail(&self.by_ip, ip, &self.settings, now);
+        }
+        fail(&self.by_user, username.to_owned(), &self.settings, now);
+    }
+
+    /**
+     * Forgets the failures of the username. Those of the address expire on their own,
+     * as logging in to any account of one's own shouldn't allow guessing more passwords.
+     */
+    pub fn record_success(&self, username: &str) {
+        self.by_user.lock().unwrap().remove(username);
+    }
+}
+
+fn remaining<K, Q>(map: &Mutex<HashMap<K, Failures>>, key: &Q, now: Instant) -> Option<Duration>
+where
+    K: Eq + Hash + std::borrow::Borrow<Q>,
+    Q: Eq + Hash + ?Sized,
+{
+    let map = map.lock().unwrap();
+    let until = map.get(key)?.locked_until?;
+    if until > now {
+        Some(until - now)
+    } else {
+        None
+    }
+}
+
+fn fail<K: Eq + Hash + Clone>(
+    map: &Mutex<HashMap<K, Failures>>,
+    key: K,
+    settings: &Settings,
+    now: Instant,
+) {
+    let mut map = map.lock().unwrap();
+    if map.len() >= MAX_TRACKED {
+        map.retain(|_, failures| now.duration_since(failures.last) < settings.max_lockout);
+    }
+    if map.len() >= MAX_TRACKED && !map.contains_key(&key) {
+        // Still full of recent failures, so make room by forgetting the one seen least recently
+        let oldest = map
+            .iter()
+            .min_by_key(|(_, failures)| failures.last)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            map.remove(&oldest);
+        }
+    }
+    let failures = map.entry(key).or_insert(Failures {
+        count: 0,
+        last: now,
+        locked_until: None,
+    });
+    if now.duration_since(failures.last) >= settings.max_lockout {
+        failures.count = 0; // Forgotten
+    }
+    failures.count += 1;
+    failures.last = now;
+    if failures.count > settings.max_failures {
+        let doublings = (failures.count - settings.max_failures - 1).min(31);
+        let lockout = settings
+            .lockout
+            .checked_mul(1 << doublings)
+            .unwrap_or(settings.max_lockout)
+            .min(settings.max_lockout);
+        failures.locked_until = Some(now + lockout);
+    }
+}
+
+fn display_ip(ip: Option<IpAddr>) -> String {
+    ip.map(|ip| ip.to_string())
+        .unwrap_or_else(|| "unix".to_owned())
+}
+
+fn get_number(var: &str, default: u64) -> u64 {
+    std::env::var(var)
+        .map(|s| {
+            s.parse()
+                .unwrap_or_else(|_| panic!("{} is not a number!", var))
+        })
+        .unwrap_or(default)
+}
@@ -0,0 +1,206 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+/**
+ * Certificates for `tls:` binds, from `SIMU_TLS_CERTS`.
+ *
+ * A comma-separated list of `name=cert.pem:key.pem` entries, picked by the name the client
+ * asks for with SNI. An entry without a name is used for clients asking for no or an unknown name.
+ * The files are read again on SIGHUP, so renewed certificates can be put in place without a restart.
+ */
+pub struct Certificates {
+    entries: Vec<Entry>,
+    loaded: RwLock<Arc<Loaded>>,
+}
+
+struct Entry {
+    name: Option<String>,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+struct Loaded {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    pub fn from_env() -> Self {
+        let entries = std::env::var("SIMU_TLS_CERTS")
+            .expect("SIMU_TLS_CERTS must be set for tls: binds!")
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|entry| {
+                let (name, files) = match entry.split_once('=') {
+                    Some((name, files)) => (Some(name.to_ascii_lowercase()), files),
+                    None => (None, entry),
+                };
+                let (cert, key) = files
+                    .split_once(':')
+                    .expect("SIMU_TLS_CERTS contents are malformed!");
+                Entry {
+                    name,
+                    cert: cert.into(),
+                    key: key.into(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let loaded = load(&entries).unwrap_or_else(|e| panic!("Cannot load certificates: {}", e));
+        Self {
+            entries,
+            loaded: RwLock::new(Arc::new(loaded)),
+        }
+    }
+
+    /**
+     * Reads the certificates again, keeping the current ones if any fail to load.
+     */
+    pub fn reload(&self) {
+        match load(&self.entries) {
+            Ok(loaded) => {
+                *self.loaded.write().unwrap() = Arc::new(loaded);
+                info!("Reloaded TLS certificates");
+            }
+            Err(e) => error!(
+                "Failed to reload TLS certificates, keeping the old ones: {}",
+                e
+            ),
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.read().unwrap().clone();
+        client_hello
+            .server_name()
+            .and_then(|name| loaded.by_name.get(&name.to_ascii_lowercase()))
+            .or(loaded.default.as_ref())
+            .cloned()
+    }
+}
+
+fn load(entries: &[Entry]) -> Result<Loaded, String> {
+    let mut loaded = Loaded {
+        by_name: HashMap::new(),
+        default: None,
+    };
+    for entry in entries {
+        let key = Arc::new(load_pair(entry)?);
+        match &entry.name {
+            Some(name) => {
+                loaded.by_name.insert(name.clone(), key);
+            }
+            None => loaded.default = Some(key),
+        }
+    }
+    Ok(loaded)
+}
+
+fn load_pair(entry: &Entry) -> Result<CertifiedKey, String> {
+    let open = |path: &PathBuf| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    };
+    let certs = rustls_pemfile::certs(&mut open(&entry.cert)?)
+        .map_err(|e| format!("{}: {}", entry.cert.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", entry.cert.display()));
+    }
+    let key = rustls_pemfile::read_all(&mut open(&entry.key)?)
+        .map_err(|e| format!("{}: {}", entry.key.display(), e))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| format!("{}: no private key found", entry.key.display()))?;
+    let key = any_supported_type(&PrivateKey(key))
+        .map_err(|_| format!("{}: unsupported private key", entry.key.display()))?;
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+/**
+ * TLS 1.2 and 1.3 with forward secret AEAD cipher suites only, rustls' defaults.
+ * HTTP/2 is offered through ALPN by actix-web itself.
+ *
+ * With `SIMU_TLS_CLIENT_CA` set to a file of CA certificates, clients may present a certificate
+ * issued by one of them. Clients without one are still let in, to log in with a password.
+ */
+pub fn server_config(certificates: Arc<Certificates>) -> ServerConfig {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match std::env::var("SIMU_TLS_CLIENT_CA") {
+        Ok(path) => {
+            let roots =
+                load_roots(&path).unwrap_or_else(|e| panic!("Cannot load client CAs: {}", e));
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+        }
+        Err(_) => builder.with_no_client_auth(),
+    };
+    builder.with_cert_resolver(certificates)
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let certs =
+        rustls_pemfile::certs(&mut BufReader::new(file)).map_err(|e| format!("{}: {}", path, e))?;
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots
+            .add(&Certificate(cert))
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if roots.is_empty() {
+        return Err(format!("{}: no certificates found", path));
+    }
+    Ok(roots)
+}
+
+/**
+ * The certificate a client presented, verified against `SIMU_TLS_CLIENT_CA`.
+ * Stored with the connection, available to each request through `HttpRequest::conn_data`.
+ */
+pub struct ClientCertificate(pub Vec<u8>);
+
+/**
+ * Picks up the client's certificate once a connection is set up, passed to `HttpServer::on_connect`.
+ */
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = stream.get_ref();
+        if let Some(leaf) = session.peer_certificates().and_then(|certs| certs.first()) {
+            data.insert(ClientCertificate(leaf.0.clone()));
+        }
+    }
+}
+
+/**
+ * Reloads the certificates whenever the server receives SIGHUP.
+ */
+pub fn reload_on_hangup(certificates: Arc<Certificates>) {
+    actix_web::rt::spawn(async move {
+        let mut hangups = signal(SignalKind::hangup()).expect("Cannot listen for SIGHUP");
+        while hangups.recv().await.is_some() {
+            certificates.reload();
+        }
+    });
+}
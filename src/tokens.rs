@@ -0,0 +1,113 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use simu::config::Config;
+use simu::{MintedToken, NewToken, TokenInfo, TokenOperation, TokenScope};
+
+use crate::file_service::error_response;
+use crate::throttle::Throttle;
+use crate::{helper, login, security};
+
+const MAX_NAME_LEN: usize = 100;
+
+/**
+ * A token to mint, as sent by the client.
+ */
+#[derive(Deserialize)]
+pub struct MintRequest {
+    name: String,
+    /// Seconds from now until the token expires, never if unset
+    expires_in: Option<u64>,
+    #[serde(flatten)]
+    scope: TokenScope,
+}
+
+#[derive(Serialize)]
+struct TokenList {
+    /// Needed to mint and revoke tokens with Basic credentials
+    csrf_token: Option<String>,
+    tokens: Vec<TokenInfo>,
+}
+
+/**
+ * Lists the user's API tokens, without their secrets.
+ */
+pub async fn list(req: HttpRequest, throttle: web::Data<Throttle>) -> HttpResponse {
+    let (credentials, attempt) = match login::credentials(&req, &throttle) {
+        Ok(login) => login,
+        Err(resp) => return *resp,
+    };
+    let result = helper::run_tokens::<Vec<TokenInfo>>(credentials, TokenOperation::List).await;
+    attempt.record(&throttle, &result);
+    match result {
+        Ok(tokens) => HttpResponse::Ok().json(TokenList {
+            csrf_token: security::csrf_token(&req),
+            tokens,
+        }),
+        Err(err) => error_response(&err, &req),
+    }
+}
+
+/**
+ * Mints a token, answering with the whole token. It is not shown again.
+ */
+pub async fn mint(
+    req: HttpRequest,
+    body: web::Json<MintRequest>,
+    config: web::Data<Config>,
+    throttle: web::Data<Throttle>,
+) -> HttpResponse {
+    let body = body.into_inner();
+    if body.name.trim().is_empty() || body.name.len() > MAX_NAME_LEN {
+        return HttpResponse::BadRequest().finish();
+    }
+    if let Some(share) = &body.scope.share {
+        if config.share(share).is_none() {
+            return HttpResponse::BadRequest().finish();
+        }
+    }
+    let expires = match body.expires_in {
+        Some(secs) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            Some(now.saturating_add(secs))
+        }
+        None => None,
+    };
+    let (credentials, attempt) = match login::credentials(&req, &throttle) {
+        Ok(login) => login,
+        Err(resp) => return *resp,
+    };
+    let new = NewToken {
+        name: body.name,
+        expires,
+        scope: body.scope,
+    };
+    let result = helper::run_tokens::<MintedToken>(credentials, TokenOperation::Mint(new)).await;
+    attempt.record(&throttle, &result);
+    match result {
+        Ok(minted) => HttpResponse::Created().json(minted),
+        Err(err) => error_response(&err, &req),
+    }
+}
+
+pub async fn revoke(
+    req: HttpRequest,
+    id: web::Path<String>,
+    throttle: web::Data<Throttle>,
+) -> HttpResponse {
+    let (credentials, attempt) = match login::credentials(&req, &throttle) {
+        Ok(login) => login,
+        Err(resp) => return *resp,
+    };
+    let operation = TokenOperation::Revoke(id.into_inner());
+    let result = helper::run_tokens::<()>(credentials, operation).await;
+    attempt.record(&throttle, &result);
+    match result {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => error_response(&err, &req),
+    }
+}
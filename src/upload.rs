@@ -0,0 +1,147 @@
+use actix_web::dev::Payload;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use simu::{Credentials, ReturnCode, UploadedFile};
+
+use crate::error::SimuError;
+use crate::helper;
+use crate::multipart::FilePart;
+
+/**
+ * A file as stored, for clients uploading with PUT.
+ */
+#[derive(Serialize)]
+pub struct Uploaded {
+    name: String,
+    size: u64,
+}
+
+impl From<UploadedFile> for Uploaded {
+    fn from(file: UploadedFile) -> Self {
+        Self {
+            name: String::from_utf8_lossy(&file.name).into_owned(),
+            size: file.size,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GroupQuery {
+    group: Option<String>,
+}
+
+/**
+ * The form of directory listings that creates a directory in it.
+ */
+#[derive(Deserialize)]
+struct NewDirectory {
+    directory: String,
+    #[serde(default)]
+    group: String,
+}
+
+/**
+ * Stores the body of a PUT as the file at the path, replacing any file already there.
+ * `?group=` picks which of the user's groups it belongs to.
+ */
+pub async fn put(
+    credentials: Credentials,
+    req: &HttpRequest,
+    share: &str,
+    path: &[u8],
+    payload: Payload,
+) -> Result<HttpResponse, SimuError> {
+    let body = futures::StreamExt::map(payload, |chunk| {
+        chunk.map_err(|_| SimuError::new(ReturnCode::InvalidInput))
+    });
+    let file =
+        helper::run_upload(credentials, share, path, group_query(req), Box::pin(body)).await?;
+    Ok(HttpResponse::Created().json(Uploaded::from(file)))
+}
+
+/**
+ * Creates the directory at the path, as WebDAV's MKCOL does, `?group=` working as for PUT.
+ */
+pub async fn mkcol(
+    credentials: Credentials,
+    req: &HttpRequest,
+    share: &str,
+    path: &[u8],
+) -> Result<HttpResponse, SimuError> {
+    helper::run_mkdir(credentials, share, path, group_query(req)).await?;
+    Ok(HttpResponse::Created().finish())
+}
+
+/**
+ * Takes the forms of a directory listing, which upload a file to the directory or create one in it,
+ * then goes back to the listing.
+ */
+pub async fn post_form(
+    credentials: Credentials,
+    req: &HttpRequest,
+    share: &str,
+    dir: &[u8],
+    mut payload: Payload,
+) -> Result<HttpResponse, SimuError> {
+    let is_multipart = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("multipart/form-data"))
+        .unwrap_or(false);
+    if is_multipart {
+        let (name, part) = FilePart::open(req, payload)
+            .await
+            .map_err(|_| SimuError::new(ReturnCode::InvalidInput))?;
+        // Some browsers send the whole path the file was picked from
+        let name = name
+            .rsplit(|b| *b == b'/' || *b == b'\\')
+            .next()
+            .unwrap_or_default();
+        let path = child_path(dir, name)?;
+        let group = part.field("group").and_then(non_empty);
+        let body = futures::stream::unfold(part, |mut part| async move {
+            let chunk = part.next_chunk().await?;
+            Some((
+                chunk.map_err(|_| SimuError::new(ReturnCode::InvalidInput)),
+                part,
+            ))
+        });
+        helper::run_upload(credentials, share, &path, group, Box::pin(body)).await?;
+    } else {
+        let form = web::Form::<NewDirectory>::from_request(req, &mut payload)
+            .await
+            .map_err(|_| SimuError::new(ReturnCode::InvalidInput))?;
+        let path = child_path(dir, form.directory.as_bytes())?;
+        let group = non_empty(&form.group);
+        helper::run_mkdir(credentials, share, &path, group).await?;
+    }
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", req.path()))
+        .finish())
+}
+
+/**
+ * The path of an entry to be created in `dir`, refusing names that would lead anywhere else.
+ */
+fn child_path(dir: &[u8], name: &[u8]) -> Result<Vec<u8>, SimuError> {
+    if matches!(name, b"" | b"." | b"..") || name.contains(&b'/') || name.contains(&0) {
+        return Err(SimuError::new(ReturnCode::InvalidInput));
+    }
+    Ok([dir, name].concat())
+}
+
+fn group_query(req: &HttpRequest) -> Option<String> {
+    web::Query::<GroupQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().group)
+}
+
+/**
+ * Forms send fields left empty, which stand for no choice.
+ */
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
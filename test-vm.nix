@@ -17,14 +17,28 @@ in pkgs.nixosTest({
           initialPassword = "testpassword";
           isNormalUser = true;
         };
+        users.simu = {
+          isSystemUser = true;
+          group = "simu";
+        };
+        groups.simu = {};
+      };
+
+      # The Nix store can't hold SUID binaries
+      security.wrappers.simu_suid_helper = {
+        source = "${simu}/bin/simu_suid_helper";
+        owner = "root";
+        group = "root";
+        setuid = true;
       };
 
       systemd.services.simu = {
+        environment.SIMU_HELPER = "/run/wrappers/bin/simu_suid_helper";
         serviceConfig = {
           ExecStart = "${simu}/bin/simu";
           Type = "simple";
           WorkingDirectory = "/data";
-          User = "root";
+          User = "simu";
         };
       };
       environment.systemPackages = [ simu pkgs.pam ];
@@ -53,5 +67,15 @@ in pkgs.nixosTest({
     client.succeed('curl --fail -o - testaccount:testpassword@server:8080/test')
     client.fail('curl --fail -o - testaccount:testpassword@server:8080/nonexistant')
     client.fail('curl --fail -o - notanaccount:testpassword@server:8080/nonexistant')
+
+    # mint an API token and use it in place of the password
+    import json
+    tokens = json.loads(client.succeed('curl --fail -s testaccount:testpassword@server:8080/.simu/tokens'))
+    minted = json.loads(client.succeed(
+        f"curl --fail -s -H 'X-CSRF-Token: {tokens['csrf_token']}' -H 'Content-Type: application/json' "
+        + "-d '{\"name\": \"test\", \"read_only\": true}' testaccount:testpassword@server:8080/.simu/tokens"
+    ))
+    client.succeed(f"curl --fail -o - -H 'Authorization: Bearer {minted['token']}' server:8080/test")
+    client.fail(f"curl --fail -o - -H 'Authorization: Bearer {minted['token']}0' server:8080/test")
   '';
 })